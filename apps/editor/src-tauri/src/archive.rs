@@ -1,156 +1,1044 @@
-use std::collections::HashMap;
-use std::fs::{File, canonicalize};
-use std::io::{self, BufReader, BufWriter, Read, Write};
-use std::path::{Path};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, canonicalize};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tauri::State;
 use tauri::ipc::Channel;
 use zip::{ZipArchive, ZipWriter};
 use zip::write::SimpleFileOptions;
-use fancy_regex::{Captures, Regex};
-use serde::Serialize;
+use tempfile::NamedTempFile;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::operation::{Cancelled, CancellationToken, Operations};
+
+mod bundle;
+mod inspect;
+mod manifest;
+mod recompress;
+mod remote;
+mod syntax;
+#[cfg(test)]
+mod test_bundles;
+mod verify;
+
+pub use bundle::{Bundles, close_archive, open_archive, save_archive, serve_asset};
+pub use inspect::inspect_assets;
+pub use verify::verify_archive;
+use manifest::{AssetRecord, Manifest, MANIFEST_NAME};
+use recompress::{RecompressOptions, recompress};
+use remote::{FailedDownload, Fetcher, RemoteOptions};
+
+const SOURCE_NAME: &str = "source.emmm";
+
+/// Size of the chunks in which assets are streamed; progress is reported
+/// after each chunk.
+const CHUNK_SIZE: usize = 1 << 20;
 
 #[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+#[allow(clippy::struct_field_names)]
 pub struct Progress {
     progress: f64,
+    bytes_done: u64,
+    bytes_total: u64,
+    /// Name of the file being processed
+    current: Option<String>,
 }
 
-#[allow(clippy::cast_precision_loss)]
-#[tauri::command]
-pub async fn archive(
-    channel: Channel<Progress>, source: String, path: String
-) -> Result<(), String> {
-    tauri::async_runtime::spawn_blocking(move || -> anyhow::Result<()> {
-        let file = File::create(&path)?;
-        let writer = BufWriter::new(file);
-        let mut zip = ZipWriter::new(writer);
-
-        let re = Regex::new(r"file:(.+?)(?=[;\]\n])")?;
-        let mut map = HashMap::<String, String>::new();
-
-        let result = re.replace_all(&source, |caps: &Captures| {
-            let file_path = &caps[1];
-            let path = Path::new(file_path);
+/// What to do when an extracted file would replace an existing one.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum CollisionPolicy {
+    Fail,
+    /// Append a numbered suffix, e.g. `photo (1).jpg`
+    #[default]
+    Rename,
+    Overwrite,
+}
 
-            if let Some(name) = path.file_name()
-                && path.is_file()
-            {
-                let key = name.to_string_lossy().to_string();
-
-                let distinct_key = if map.contains_key(&key) {
-                    let mut n: u32 = 0;
-                    loop {
-                        let new_key = format!("{n}_{key}");
-                        if !map.contains_key(&new_key) { break new_key; }
-                        n += 1;
-                    }
-                } else { key };
+/// Caps that protect against zip bombs and corrupted bundles.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExtractLimits {
+    /// Total uncompressed size in bytes
+    total_size: u64,
+    /// Uncompressed size of a single entry in bytes
+    entry_size: u64,
+    entries: usize,
+    /// Uncompressed to compressed size, for entries larger than `CHUNK_SIZE`
+    compression_ratio: u64,
+}
 
-                map.insert(distinct_key.clone(), file_path.to_string());
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            total_size: 4 << 30,
+            entry_size: 1 << 30,
+            entries: 10_000,
+            compression_ratio: 100,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum Limit {
+    TotalSize,
+    EntrySize,
+    EntryCount,
+    CompressionRatio,
+}
 
-                format!("asset:{distinct_key}")
-            } else {
-                log::debug!("bad path: {file_path}");
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::TotalSize => write!(f, "total size"),
+            Limit::EntrySize => write!(f, "entry size"),
+            Limit::EntryCount => write!(f, "entry count"),
+            Limit::CompressionRatio => write!(f, "compression ratio"),
+        }
+    }
+}
 
-                caps[0].to_string()
+impl ExtractLimits {
+    fn exceeded(entry: &str, limit: Limit, max: u64) -> anyhow::Error {
+        ArchiveError::LimitExceeded { entry: entry.to_string(), limit, max }.into()
+    }
+
+    /// Checks the sizes declared in the central directory before anything is
+    /// extracted, returning the total uncompressed size.
+    fn check_declared<R: Read + Seek>(&self, zip: &mut ZipArchive<R>) -> anyhow::Result<u64> {
+        if zip.len() > self.entries {
+            let entry = zip.name_for_index(self.entries).unwrap_or_default().to_string();
+            return Err(Self::exceeded(&entry, Limit::EntryCount, self.entries as u64));
+        }
+        let mut total: u64 = 0;
+        for i in 0..zip.len() {
+            let file = zip.by_index_raw(i)?;
+            let size = file.size();
+            if size > self.entry_size {
+                return Err(Self::exceeded(file.name(), Limit::EntrySize, self.entry_size));
             }
-        });
+            total = total.saturating_add(size);
+            if total > self.total_size {
+                return Err(Self::exceeded(file.name(), Limit::TotalSize, self.total_size));
+            }
+            if self.exceeds_ratio(size, file.compressed_size()) {
+                return Err(Self::exceeded(file.name(), Limit::CompressionRatio, self.compression_ratio));
+            }
+        }
+        Ok(total)
+    }
 
-        let options = SimpleFileOptions::default()
-            .compression_method(zip::CompressionMethod::Deflated);
+    fn exceeds_ratio(&self, size: u64, compressed: u64) -> bool {
+        size > CHUNK_SIZE as u64 && size / compressed.max(1) > self.compression_ratio
+    }
 
-        // 2. Write the modified source file
-        zip.start_file("source.emmm", options)?;
-        zip.write_all(result.as_bytes())?;
+    /// Reads at most one byte more than an entry of `compressed` bytes may
+    /// actually inflate to, given that `extracted` bytes have been extracted
+    /// before it.
+    fn entry_budget(&self, compressed: u64, extracted: u64) -> u64 {
+        let inflated = compressed.saturating_mul(self.compression_ratio + 1).max(CHUNK_SIZE as u64);
+        self.entry_size.min(self.total_size.saturating_sub(extracted)).min(inflated) + 1
+    }
 
-        // 3. Write assets with progress reporting
-        let total_files = map.len();
-        let mut processed = 0;
+    /// Checks the number of bytes actually read for an entry, which may differ
+    /// from the declared size in a malicious archive.
+    fn check_actual(
+        &self, entry: &str, size: u64, compressed: u64, extracted: u64
+    ) -> anyhow::Result<()> {
+        if size > self.entry_size {
+            Err(Self::exceeded(entry, Limit::EntrySize, self.entry_size))
+        } else if extracted + size > self.total_size {
+            Err(Self::exceeded(entry, Limit::TotalSize, self.total_size))
+        } else if self.exceeds_ratio(size, compressed) {
+            Err(Self::exceeded(entry, Limit::CompressionRatio, self.compression_ratio))
+        } else {
+            Ok(())
+        }
+    }
+}
 
-        let assets_path = Path::new("assets/");
-        zip.add_directory(assets_path.to_string_lossy(), options)?;
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UnarchiveOptions {
+    /// Applies to files already present in the output directory. Entries of
+    /// the same bundle that share a name are always renamed.
+    on_collision: CollisionPolicy,
+    /// Extract into a new subdirectory named after the bundle
+    subdirectory: bool,
+    limits: ExtractLimits,
+}
 
-        for (filename, source_path) in map {
-            processed += 1;
-            let progress = f64::from(processed) / total_files as f64;
+impl Default for UnarchiveOptions {
+    fn default() -> Self {
+        UnarchiveOptions {
+            on_collision: CollisionPolicy::default(),
+            subdirectory: true,
+            limits: ExtractLimits::default(),
+        }
+    }
+}
 
-            let mut buf = Vec::new();
-            match File::open(&source_path) {
-                Ok(mut f) => {
-                    f.read_to_end(&mut buf)?;
-                    zip.start_file(assets_path.join(filename).to_string_lossy(), options)?;
-                    zip.write_all(&buf)?;
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ArchiveOptions {
+    remote: RemoteOptions,
+    /// Pass image assets through the compression pipeline before storing them
+    recompress: Option<RecompressOptions>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveReport {
+    /// Remote images that could not be bundled; their references are kept
+    failed_downloads: Vec<FailedDownload>,
+    /// Number of images replaced by a smaller recompressed version
+    recompressed: usize,
+    bytes_saved: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Unarchived {
+    source: String,
+    /// Directory the assets were extracted to
+    directory: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind", content = "data")]
+pub enum ArchiveError {
+    #[serde(rename_all = "camelCase")]
+    Cancelled,
+    #[serde(rename_all = "camelCase")]
+    Collision { path: String },
+    #[serde(rename_all = "camelCase")]
+    LimitExceeded { entry: String, limit: Limit, max: u64 },
+    #[serde(rename_all = "camelCase")]
+    Failed { msg: String },
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Cancelled => write!(f, "operation cancelled"),
+            ArchiveError::Collision { path } => write!(f, "file already exists: {path}"),
+            ArchiveError::LimitExceeded { entry, limit, max } =>
+                write!(f, "{entry} exceeds the {limit} limit of {max}"),
+            ArchiveError::Failed { msg } => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<anyhow::Error> for ArchiveError {
+    fn from(e: anyhow::Error) -> Self {
+        log::debug!("{e:?}");
+        match e.downcast::<ArchiveError>() {
+            Ok(e) => e,
+            Err(e) if e.is::<Cancelled>() => ArchiveError::Cancelled,
+            Err(e) => ArchiveError::Failed { msg: e.to_string() },
+        }
+    }
+}
+
+struct ProgressTracker<'a> {
+    channel: &'a Channel<Progress>,
+    token: &'a CancellationToken,
+    bytes_done: u64,
+    bytes_total: u64,
+    buf: Vec<u8>,
+}
+
+impl<'a> ProgressTracker<'a> {
+    fn new(
+        channel: &'a Channel<Progress>, token: &'a CancellationToken, bytes_total: u64
+    ) -> Self {
+        ProgressTracker {
+            channel, token, bytes_done: 0, bytes_total, buf: vec![0; CHUNK_SIZE]
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn report(&self, current: Option<&str>) -> anyhow::Result<()> {
+        let progress = if self.bytes_total == 0 { 1.0 }
+            else { (self.bytes_done as f64 / self.bytes_total as f64).min(1.0) };
+        self.channel.send(Progress {
+            progress,
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            current: current.map(str::to_string),
+        })?;
+        Ok(())
+    }
+
+    /// Streams `reader` into `writer`, returning the number of bytes copied.
+    fn copy(
+        &mut self, current: &str, reader: &mut impl Read, writer: &mut impl Write
+    ) -> anyhow::Result<u64> {
+        let mut copied = 0;
+        loop {
+            self.token.check()?;
+            let n = match reader.read(&mut self.buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            writer.write_all(&self.buf[..n])?;
+            copied += n as u64;
+            self.bytes_done += n as u64;
+            self.report(Some(current))?;
+        }
+        Ok(copied)
+    }
+}
+
+struct Asset {
+    name: String,
+    /// All paths or URLs in the source that refer to this content
+    original_paths: Vec<String>,
+    /// Where the content is read from when it is written into the bundle
+    local: PathBuf,
+    size: u64,
+}
+
+/// Returns the hex SHA-256 and the size of a file.
+fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Assets are named by their content hash, so identical files are stored once
+/// and names stay stable across re-archives.
+fn asset_name(hash: &str, ext: Option<&str>) -> String {
+    match ext {
+        Some(ext) => format!("{hash}.{}", ext.to_lowercase()),
+        None => hash.to_string(),
+    }
+}
+
+/// Creates a temporary file in the directory of `path`, so that it can take
+/// the place of `path` once complete.
+fn temp_file_beside(path: &Path) -> io::Result<NamedTempFile> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    NamedTempFile::new_in(dir)
+}
+
+/// Finds the local files referenced by `source`, as well as the remote images
+/// if a `fetcher` is given, and rewrites the references to point at the
+/// assets they will be stored as. Returns the rewritten source and the assets
+/// keyed by content hash.
+fn collect_files(
+    source: &str, token: &CancellationToken, mut fetcher: Option<&mut Fetcher>
+) -> anyhow::Result<(String, BTreeMap<String, Asset>)> {
+    // content hash -> asset; ordered so that the bundle layout is stable
+    let mut assets = BTreeMap::<String, Asset>::new();
+    // file path or URL -> asset name
+    let mut names = HashMap::<String, String>::new();
+
+    let result = syntax::rewrite_arguments(source, |value| {
+        if let Some(name) = names.get(value) {
+            return Some(format!("asset:{name}"));
+        }
+        if token.is_cancelled() {
+            return None;
+        }
+
+        let (hash, name, local, size) = if let Some(file_path) = value.strip_prefix("file:") {
+            let path = Path::new(file_path);
+            if !path.is_file() {
+                log::warn!("referenced file does not exist: {file_path}");
+                return None;
+            }
+            match hash_file(path) {
+                Ok((hash, size)) => {
+                    let ext = path.extension().map(|e| e.to_string_lossy());
+                    let name = asset_name(&hash, ext.as_deref());
+                    (hash, name, path.to_path_buf(), size)
                 }
                 Err(e) => {
-                    return Err(anyhow::anyhow!("Failed to read {source_path}: {e}"));
+                    log::warn!("failed to hash {file_path}: {e}");
+                    return None;
                 }
             }
+        } else if (value.starts_with("http:") || value.starts_with("https:"))
+            && let Some(fetcher) = fetcher.as_deref_mut()
+            && !fetcher.has_failed(value)
+        {
+            match fetcher.download(value, token) {
+                Ok(Some(download)) => {
+                    let name = asset_name(&download.hash, Some(&download.ext));
+                    let local = download.file.path().to_path_buf();
+                    fetcher.downloads.push(download.file);
+                    (download.hash, name, local, download.size)
+                }
+                Ok(None) => return None,
+                Err(e) => {
+                    log::warn!("failed to download {value}: {e:?}");
+                    fetcher.failed.push(FailedDownload {
+                        url: value.to_string(), reason: e.to_string()
+                    });
+                    return None;
+                }
+            }
+        } else {
+            return None;
+        };
+
+        let asset = assets.entry(hash).or_insert(Asset {
+            name, original_paths: vec![], local, size
+        });
+        asset.original_paths.push(value.strip_prefix("file:").unwrap_or(value).to_string());
+        names.insert(value.to_string(), asset.name.clone());
+        Some(format!("asset:{}", asset.name))
+    });
+    token.check()?;
+    Ok((result, assets))
+}
 
-            channel.send(Progress { progress })?;
+/// Streams a local file into the bundle as an asset.
+fn write_asset<W: Write + Seek>(
+    zip: &mut ZipWriter<W>, tracker: &mut ProgressTracker, options: SimpleFileOptions,
+    hash: String, asset: Asset
+) -> anyhow::Result<AssetRecord> {
+    let Asset { name, original_paths, local, .. } = asset;
+    let mut f = File::open(&local)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", original_paths[0]))?;
+    zip.start_file(Path::new("assets/").join(&name).to_string_lossy(), options)?;
+    let size = tracker.copy(&name, &mut f, zip)?;
+    Ok(AssetRecord::new(name, original_paths, size, hash))
+}
+
+fn write_archive(
+    channel: &Channel<Progress>, token: &CancellationToken, source: &str, path: &Path,
+    options: &ArchiveOptions,
+) -> anyhow::Result<ArchiveReport> {
+    // keeps the downloaded images around until they are written
+    let mut fetcher = options.remote.fetch
+        .then(|| Fetcher::new(&options.remote))
+        .transpose()?;
+    let (mut result, mut assets) = collect_files(source, token, fetcher.as_mut())?;
+    // keeps the recompressed images around as well
+    let recompression = match &options.recompress {
+        Some(recompress_options) => {
+            let recompression = recompress(&mut assets, recompress_options, channel, token)?;
+            result = syntax::rewrite_arguments(&result, |value| {
+                let name = value.strip_prefix("asset:")?;
+                recompression.renamed.get(name).map(|n| format!("asset:{n}"))
+            });
+            Some(recompression)
         }
+        None => None,
+    };
 
-        zip.finish()?;
+    // an existing bundle at `path` is only replaced once this one is complete
+    let mut temp = temp_file_beside(path)?;
+    let mut zip = ZipWriter::new(BufWriter::new(temp.as_file_mut()));
 
-        Ok(())
+    let options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+
+    // 2. Write the modified source file
+    zip.start_file(SOURCE_NAME, options)?;
+    zip.write_all(result.as_bytes())?;
+
+    // 3. Stream assets with progress reporting
+    let bytes_total = assets.values().map(|a| a.size).sum();
+    let mut tracker = ProgressTracker::new(channel, token, bytes_total);
+
+    zip.add_directory("assets/", options)?;
+
+    let mut records = Vec::with_capacity(assets.len());
+    for (hash, asset) in assets {
+        records.push(write_asset(&mut zip, &mut tracker, options, hash, asset)?);
+    }
+
+    // 4. Write the manifest
+    zip.start_file(MANIFEST_NAME, options)?;
+    serde_json::to_writer_pretty(&mut zip, &Manifest::new(records)?)?;
+
+    zip.finish()?.flush()?;
+    temp.persist(path)?;
+
+    Ok(ArchiveReport {
+        failed_downloads: fetcher.map(|f| f.failed).unwrap_or_default(),
+        recompressed: recompression.as_ref().map_or(0, |r| r.renamed.len()),
+        bytes_saved: recompression.map_or(0, |r| r.bytes_saved),
     })
-    .await
-    .map_err(|e| e.to_string())?
-    .map_err(|e| e.to_string())
 }
 
-#[allow(clippy::cast_precision_loss)]
 #[tauri::command]
-pub async fn unarchive(
-    channel: Channel<Progress>, path: String, output: String
-) -> Result<String, String> {
-    tauri::async_runtime::spawn_blocking(move || -> anyhow::Result<String> {
-        let file = File::open(&path)?;
-        let reader = BufReader::new(file);
-        let mut zip = ZipArchive::new(reader)?;
-
-        let base_path = canonicalize(output)?;
-        let len = zip.len();
-
-        let re = Regex::new(r"asset:(.+?)(?=[;\]\n])")?;
-        let mut map = HashMap::<String, String>::new();
-
-        for i in 0..len {
-            let mut file = zip.by_index(i)?;
-            if let Some(file_path) = file.enclosed_name()
-                && let Some(file_name) = file_path.file_name()
-                && !file_path.eq(Path::new("source.emmm"))
-                && file.is_file()
-            {
-                let full_path = base_path.join(file_name);
-                map.insert(
-                    file_name.to_string_lossy().to_string(),
-                    full_path.to_string_lossy().to_string());
-
-                let created_file = File::create(full_path)?;
-                let mut writer = BufWriter::new(created_file);
-                io::copy(&mut file, &mut writer)?;
-                log::debug!("copied {}", file.name());
-            } else {
-                log::debug!("skipping {}", file.name());
+#[allow(clippy::needless_pass_by_value)]
+pub async fn archive(
+    channel: Channel<Progress>, source: String, path: String,
+    options: Option<ArchiveOptions>, id: String,
+    operations: State<'_, Arc<Operations>>,
+) -> Result<ArchiveReport, ArchiveError> {
+    let operation = operations.register(id);
+    tauri::async_runtime::spawn_blocking(move || {
+        write_archive(
+            &channel, operation.token(), &source, Path::new(&path),
+            &options.unwrap_or_default())
+    })
+    .await
+    .map_err(|e| ArchiveError::Failed { msg: e.to_string() })?
+    .map_err(ArchiveError::from)
+}
+
+/// Returns the last component of a path that may come from another platform,
+/// or of a URL, or `None` if it is not usable as a file name.
+fn foreign_file_name(path: &str) -> Option<&str> {
+    let path = if path.starts_with("http:") || path.starts_with("https:") {
+        path.split(['?', '#']).next()?
+    } else {
+        path
+    };
+    let name = path.rsplit(['/', '\\']).next()?;
+    (!name.is_empty() && name != "." && name != "..").then_some(name)
+}
+
+/// Inserts a numbered suffix before the extension: `a.png` -> `a (1).png`.
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem} ({n}).{}", ext.to_string_lossy()),
+        None => format!("{stem} ({n})"),
+    };
+    path.with_file_name(name)
+}
+
+/// Creates a directory that did not exist before, adding a suffix if needed.
+fn create_fresh_dir(path: &Path) -> io::Result<PathBuf> {
+    let mut candidate = path.to_path_buf();
+    let mut n = 0;
+    loop {
+        match fs::create_dir(&candidate) {
+            Ok(()) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                n += 1;
+                candidate = numbered(path, n);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Decides where to extract a file that would like to be at `path`, given the
+/// paths `used` by files extracted earlier from the same bundle.
+fn resolve_collision(
+    path: PathBuf, policy: CollisionPolicy, used: &HashSet<PathBuf>
+) -> anyhow::Result<PathBuf> {
+    let in_bundle = used.contains(&path);
+    if !in_bundle && !path.exists() {
+        return Ok(path);
+    }
+    match policy {
+        _ if in_bundle => {}
+        CollisionPolicy::Overwrite => return Ok(path),
+        CollisionPolicy::Fail => return Err(ArchiveError::Collision {
+            path: path.to_string_lossy().to_string()
+        }.into()),
+        CollisionPolicy::Rename => {}
+    }
+    let mut n = 1;
+    loop {
+        let candidate = numbered(&path, n);
+        if !used.contains(&candidate) && !candidate.exists() {
+            return Ok(candidate);
+        }
+        n += 1;
+    }
+}
+
+/// Moves extracted files to where they belong, recording in `created` those
+/// that did not replace an existing file.
+fn move_into_place(
+    pending: Vec<(NamedTempFile, PathBuf)>, policy: CollisionPolicy,
+    created: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    for (temp, path) in pending {
+        let existed = path.exists();
+        match policy {
+            CollisionPolicy::Overwrite => temp.persist(&path)?,
+            _ => temp.persist_noclobber(&path)?,
+        };
+        if !existed {
+            created.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the source of a bundle, given that `extracted` bytes have been
+/// extracted before it.
+fn read_source<R: Read + Seek>(
+    zip: &mut ZipArchive<R>, limits: &ExtractLimits, extracted: u64
+) -> anyhow::Result<String> {
+    let mut source = String::new();
+    let entry = zip.by_name(SOURCE_NAME)?;
+    let compressed = entry.compressed_size();
+    let size = entry
+        .take(limits.entry_budget(compressed, extracted))
+        .read_to_string(&mut source)?;
+    limits.check_actual(SOURCE_NAME, size as u64, compressed, extracted)?;
+    Ok(source)
+}
+
+/// Extracts the assets of a bundle, recording every file and directory it
+/// creates in `created` so that they can be cleaned up on failure.
+fn extract_archive(
+    channel: &Channel<Progress>, token: &CancellationToken,
+    path: &Path, output: &Path, options: &UnarchiveOptions,
+    created: &mut Vec<PathBuf>
+) -> anyhow::Result<Unarchived> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut zip = ZipArchive::new(reader)?;
+    let limits = &options.limits;
+    let bytes_total = limits.check_declared(&mut zip)?;
+
+    // asset name -> original file name
+    let mut original_names = HashMap::<String, String>::new();
+    match Manifest::read_from(&mut zip, limits)? {
+        Some(manifest) => {
+            log::debug!("bundle format {}, created {} by kfgui {}",
+                manifest.format_version, manifest.created, manifest.app_version);
+            for asset in manifest.assets {
+                let entry = Path::new("assets/").join(&asset.name);
+                if zip.index_for_name(&entry.to_string_lossy()).is_none() {
+                    log::warn!("asset listed in manifest is missing: {}", asset.name);
+                }
+                if let Some(name) = asset.original_paths.first()
+                    .and_then(|p| foreign_file_name(p))
+                {
+                    // the asset may have been recompressed into another format
+                    let mut name = PathBuf::from(name);
+                    let ext = Path::new(&asset.name).extension();
+                    if ext.is_some_and(|e| !name.extension()
+                        .is_some_and(|n| n.eq_ignore_ascii_case(e)))
+                    {
+                        name.set_extension(ext.unwrap_or_default());
+                    }
+                    original_names.insert(asset.name, name.to_string_lossy().to_string());
+                }
             }
-            channel.send(Progress { progress: (i as f64) / (len as f64) })?;
         }
+        None => log::debug!("legacy bundle without manifest"),
+    }
 
-        let mut source = String::new();
-        zip.by_name("source.emmm")?.read_to_string(&mut source)?;
+    let mut base_path = canonicalize(output)?;
+    if options.subdirectory {
+        let stem = path.file_stem().unwrap_or("bundle".as_ref());
+        base_path = create_fresh_dir(&base_path.join(stem))?;
+        created.push(base_path.clone());
+    }
+    let len = zip.len();
+    let mut tracker = ProgressTracker::new(channel, token, bytes_total);
 
-        let result = re.replace_all(&source, |caps: &Captures| {
-            let id = &caps[1];
-            if let Some(v) = map.get(id) {
-                format!("file:{v}")
-            } else {
-                log::debug!("failed to resolve asset: {id}");
+    let mut map = HashMap::<String, String>::new();
+    let mut used = HashSet::<PathBuf>::new();
+    let mut extracted: u64 = 0;
+    // files are only moved into place once the whole bundle has been read, so
+    // that a failure never leaves an existing file truncated or half-written
+    let mut pending = Vec::<(NamedTempFile, PathBuf)>::new();
 
-                "invalid-".to_string() + &caps[0]
+    for i in 0..len {
+        let mut file = zip.by_index(i)?;
+        if let Some(file_path) = file.enclosed_name()
+            && let Some(file_name) = file_path.file_name()
+            && !file_path.eq(Path::new(SOURCE_NAME))
+            && !file_path.eq(Path::new(MANIFEST_NAME))
+            && file.is_file()
+        {
+            let id = file.name().strip_prefix("assets/").unwrap_or(file.name()).to_string();
+            let wanted = match original_names.get(&id) {
+                Some(name) => base_path.join(name),
+                None => base_path.join(file_name),
+            };
+            let full_path = resolve_collision(wanted, options.on_collision, &used)?;
+            used.insert(full_path.clone());
+            map.insert(id, full_path.to_string_lossy().to_string());
+
+            let mut temp = temp_file_beside(&full_path)?;
+            let name = file.name().to_string();
+            let mut writer = BufWriter::new(temp.as_file_mut());
+            let compressed = file.compressed_size();
+            let mut limited = (&mut file).take(limits.entry_budget(compressed, extracted));
+            let size = tracker.copy(&name, &mut limited, &mut writer)?;
+            limits.check_actual(&name, size, compressed, extracted)?;
+            extracted += size;
+            writer.flush()?;
+            drop(writer);
+            pending.push((temp, full_path));
+            log::debug!("copied {name}");
+        } else {
+            log::debug!("skipping {}", file.name());
+            tracker.bytes_done += file.size();
+            tracker.report(None)?;
+        }
+    }
+
+    let source = read_source(&mut zip, limits, extracted)?;
+
+    let result = syntax::rewrite_arguments(&source, |value| {
+        let id = value.strip_prefix("asset:")?;
+        if let Some(v) = map.get(id) {
+            Some(format!("file:{v}"))
+        } else {
+            log::debug!("failed to resolve asset: {id}");
+            Some(format!("invalid-{value}"))
+        }
+    });
+
+    move_into_place(pending, options.on_collision, created)?;
+
+    Ok(Unarchived {
+        source: result,
+        directory: base_path.to_string_lossy().to_string(),
+    })
+}
+
+/// Like `extract_archive`, removing everything it created if it fails.
+fn extract_or_clean_up(
+    channel: &Channel<Progress>, token: &CancellationToken, path: &Path, output: &Path,
+    options: &UnarchiveOptions,
+) -> anyhow::Result<Unarchived> {
+    let mut created = Vec::new();
+    let result = extract_archive(channel, token, path, output, options, &mut created);
+    if result.is_err() {
+        // files before the directories containing them
+        for path in created.iter().rev() {
+            let removed =
+                if path.is_dir() { fs::remove_dir(path) } else { fs::remove_file(path) };
+            if let Err(e) = removed {
+                log::warn!("failed to remove {}: {e}", path.display());
             }
-        });
+        }
+    }
+    result
+}
 
-        Ok(result.to_string())
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn unarchive(
+    channel: Channel<Progress>, path: String, output: String,
+    options: Option<UnarchiveOptions>, id: String,
+    operations: State<'_, Arc<Operations>>,
+) -> Result<Unarchived, ArchiveError> {
+    let operation = operations.register(id);
+    tauri::async_runtime::spawn_blocking(move || {
+        extract_or_clean_up(
+            &channel, operation.token(), Path::new(&path), Path::new(&output),
+            &options.unwrap_or_default())
     })
     .await
-    .map_err(|e| { log::debug!("{e:?}"); e.to_string() } )?
-    .map_err(|e| { log::debug!("{e:?}"); e.to_string() } )
+    .map_err(|e| ArchiveError::Failed { msg: e.to_string() })?
+    .map_err(ArchiveError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tempfile::TempDir;
+
+    use crate::compress::test_images::noise;
+
+    use super::test_bundles::bundle;
+    use super::*;
+
+    fn channel() -> Channel<Progress> {
+        Channel::new(|_| Ok(()))
+    }
+
+    /// Writes a file into `dir` and returns its path.
+    fn file(dir: &TempDir, name: &str, data: &[u8]) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, data).unwrap();
+        path.display().to_string()
+    }
+
+    /// Extracts `data`, stored as `doc.zip`, into the `out` directory.
+    fn extract(
+        dir: &TempDir, data: &[u8], options: &UnarchiveOptions, channel: &Channel<Progress>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<Unarchived> {
+        let path = dir.path().join("doc.zip");
+        fs::write(&path, data).unwrap();
+        fs::create_dir_all(dir.path().join("out")).unwrap();
+        extract_or_clean_up(channel, token, &path, &dir.path().join("out"), options)
+    }
+
+    fn unarchive(dir: &TempDir, data: &[u8], options: &UnarchiveOptions) -> anyhow::Result<Unarchived> {
+        extract(dir, data, options, &channel(), &CancellationToken::default())
+    }
+
+    fn legacy_bundle() -> Vec<u8> {
+        bundle(None, &[(SOURCE_NAME, b"[.image asset:x.png;]"), ("assets/x.png", b"xxx")])
+    }
+
+    #[test]
+    fn stores_identical_files_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = file(&dir, "a.png", b"same");
+        let copy = file(&dir, "copy.png", b"same");
+        let other = file(&dir, "other.PNG", b"other");
+        let source = format!("[.image file:{a};][.image file:{copy};][.image file:{a};][.image file:{other};]");
+        let path = dir.path().join("doc.zip");
+        write_archive(&channel(), &CancellationToken::default(), &source, &path,
+            &ArchiveOptions::default()).unwrap();
+
+        let same = asset_name(&format!("{:x}", Sha256::digest(b"same")), Some("png"));
+        let other = asset_name(&format!("{:x}", Sha256::digest(b"other")), Some("png"));
+        assert_eq!(Path::new(&other).extension().unwrap(), "png");
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut stored = String::new();
+        zip.by_name(SOURCE_NAME).unwrap().read_to_string(&mut stored).unwrap();
+        assert_eq!(stored, format!(
+            "[.image asset:{same};][.image asset:{same};][.image asset:{same};][.image asset:{other};]"));
+        // the directory and two files
+        assert_eq!(zip.file_names().filter(|n| n.starts_with("assets/")).count(), 3);
+
+        let manifest = Manifest::read_from(&mut zip, &ExtractLimits::default()).unwrap().unwrap();
+        let record = manifest.assets.iter().find(|r| r.name == same).unwrap();
+        assert_eq!(record.original_paths, [a, copy]);
+        assert_eq!(manifest.assets.len(), 2);
+    }
+
+    #[test]
+    fn temp_file_beside_bare_file_name() {
+        // the parent of a bare file name is empty, which is not a directory
+        let temp = temp_file_beside(Path::new("doc.zip")).unwrap();
+        assert_eq!(temp.path().parent().unwrap(), std::env::current_dir().unwrap());
+    }
+
+    #[test]
+    fn recompressed_assets_are_renamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut next = noise();
+        let noisy = image::RgbaImage::from_fn(96, 96, |_, _| {
+            let [r, g, b, _] = next().to_le_bytes();
+            image::Rgba([r, g, b, 255])
+        });
+        let noisy_path = dir.path().join("noisy.png");
+        noisy.save(&noisy_path).unwrap();
+        let noisy_path = noisy_path.display().to_string();
+        let path = dir.path().join("doc.zip");
+        let options = ArchiveOptions {
+            recompress: Some(RecompressOptions {
+                max_width: None, max_size: 8000, keep_copyright: false,
+            }),
+            ..Default::default()
+        };
+        let report = write_archive(&channel(), &CancellationToken::default(),
+            &format!("[.image file:{noisy_path};]"), &path, &options).unwrap();
+        assert_eq!(report.recompressed, 1);
+
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let manifest = Manifest::read_from(&mut zip, &ExtractLimits::default()).unwrap().unwrap();
+        let [record] = &manifest.assets[..] else { panic!("one asset expected") };
+        let mut data = Vec::new();
+        zip.by_name(&format!("assets/{}", record.name)).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(record.name, asset_name(&format!("{:x}", Sha256::digest(&data)), Some("jpg")));
+        assert_eq!((record.size, record.mime.as_str()), (data.len() as u64, "image/jpeg"));
+        assert_eq!(report.bytes_saved, fs::metadata(&noisy_path).unwrap().len() - record.size);
+        assert_eq!(record.original_paths, [noisy_path]);
+        let mut stored = String::new();
+        zip.by_name(SOURCE_NAME).unwrap().read_to_string(&mut stored).unwrap();
+        assert_eq!(stored, format!("[.image asset:{};]", record.name));
+    }
+
+    #[test]
+    fn failed_archive_keeps_existing_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = format!("[.image file:{};]", file(&dir, "a.png", b"aaa"));
+        let path = dir.path().join("doc.zip");
+        fs::write(&path, b"old bundle").unwrap();
+
+        let token = CancellationToken::default();
+        token.cancel();
+        assert!(write_archive(&channel(), &token, &source, &path, &ArchiveOptions::default())
+            .is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old bundle");
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        write_archive(&channel(), &CancellationToken::default(), &source, &path,
+            &ArchiveOptions::default()).unwrap();
+        assert!(ZipArchive::new(File::open(&path).unwrap()).unwrap().by_name(SOURCE_NAME).is_ok());
+    }
+
+    #[test]
+    fn collision_policies() {
+        let in_place = |on_collision| UnarchiveOptions { on_collision, subdirectory: false, ..Default::default() };
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        fs::write(out.join("x.png"), b"old").unwrap();
+
+        let e = unarchive(&dir, &legacy_bundle(), &in_place(CollisionPolicy::Fail)).err().unwrap();
+        assert!(matches!(ArchiveError::from(e), ArchiveError::Collision { path } if path.ends_with("x.png")));
+        assert_eq!(fs::read(out.join("x.png")).unwrap(), b"old");
+
+        unarchive(&dir, &legacy_bundle(), &in_place(CollisionPolicy::Rename)).unwrap();
+        assert_eq!(fs::read(out.join("x.png")).unwrap(), b"old");
+        assert_eq!(fs::read(out.join("x (1).png")).unwrap(), b"xxx");
+
+        unarchive(&dir, &legacy_bundle(), &in_place(CollisionPolicy::Overwrite)).unwrap();
+        assert_eq!(fs::read(out.join("x.png")).unwrap(), b"xxx");
+        assert!(!out.join("x (2).png").exists());
+    }
+
+    #[test]
+    fn failed_overwrite_keeps_existing_file() {
+        let options = UnarchiveOptions {
+            on_collision: CollisionPolicy::Overwrite, subdirectory: false, ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        fs::write(out.join("x.png"), b"old").unwrap();
+
+        // fails after x.png has been read, as the source is missing
+        assert!(unarchive(&dir, &bundle(None, &[("assets/x.png", b"xxx")]), &options).is_err());
+        assert_eq!(fs::read(out.join("x.png")).unwrap(), b"old");
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn renames_entries_sharing_a_name() {
+        let data = bundle(None, &[
+            (SOURCE_NAME, b"[.image asset:x.png;][.image asset:sub/x.png;]"),
+            ("assets/x.png", b"xxx"), ("assets/sub/x.png", b"yyy"),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = UnarchiveOptions { on_collision: CollisionPolicy::Overwrite, ..Default::default() };
+        let unarchived = unarchive(&dir, &data, &options).unwrap();
+        let directory = Path::new(&unarchived.directory);
+        assert_eq!(fs::read(directory.join("x.png")).unwrap(), b"xxx");
+        assert_eq!(fs::read(directory.join("x (1).png")).unwrap(), b"yyy");
+    }
+
+    #[test]
+    fn extracts_into_fresh_subdirectory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("out/doc")).unwrap();
+        let unarchived = unarchive(&dir, &legacy_bundle(), &UnarchiveOptions::default()).unwrap();
+        let directory = Path::new(&unarchived.directory);
+        assert_eq!(directory.file_name().unwrap(), "doc (1)");
+        assert_eq!(fs::read_dir(dir.path().join("out/doc")).unwrap().count(), 0);
+        assert_eq!(fs::read(directory.join("x.png")).unwrap(), b"xxx");
+        assert_eq!(unarchived.source,
+            format!("[.image file:{};]", directory.join("x.png").display()));
+    }
+
+    #[test]
+    fn extract_limits() {
+        let limit = |data: &[u8], limits| {
+            let dir = tempfile::tempdir().unwrap();
+            let e = unarchive(&dir, data, &UnarchiveOptions { limits, ..Default::default() })
+                .err().unwrap();
+            assert_eq!(fs::read_dir(dir.path().join("out")).unwrap().count(), 0);
+            match ArchiveError::from(e) {
+                ArchiveError::LimitExceeded { limit, max, .. } => (limit, max),
+                e => panic!("{e}"),
+            }
+        };
+        let defaults = ExtractLimits::default();
+        let data = legacy_bundle();
+        assert!(matches!(limit(&data, ExtractLimits { entries: 1, ..defaults }),
+            (Limit::EntryCount, 1)));
+        assert!(matches!(limit(&data, ExtractLimits { entry_size: 5, ..defaults }),
+            (Limit::EntrySize, 5)));
+        assert!(matches!(limit(&data, ExtractLimits { total_size: 23, ..defaults }),
+            (Limit::TotalSize, 23)));
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file(SOURCE_NAME, options).unwrap();
+        zip.write_all(b"[.image asset:zeros.bin;]").unwrap();
+        zip.start_file("assets/zeros.bin", options).unwrap();
+        zip.write_all(&vec![0; 2 * CHUNK_SIZE]).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        assert!(matches!(limit(&data, defaults), (Limit::CompressionRatio, 100)));
+    }
+
+    #[test]
+    fn checks_inflated_sizes() {
+        let limits = ExtractLimits { total_size: 3 * CHUNK_SIZE as u64, ..Default::default() };
+        // declared sizes may lie, so reading stops just past what is allowed
+        assert_eq!(limits.entry_budget(1000, 0), CHUNK_SIZE as u64 + 1);
+        assert_eq!(limits.entry_budget(1 << 20, CHUNK_SIZE as u64), 2 * CHUNK_SIZE as u64 + 1);
+        let exceeded = |size, compressed, extracted| {
+            match ArchiveError::from(limits.check_actual("x", size, compressed, extracted).err()?) {
+                ArchiveError::LimitExceeded { limit, .. } => Some(limit),
+                e => panic!("{e}"),
+            }
+        };
+        assert!(exceeded(CHUNK_SIZE as u64, 1, 0).is_none());
+        assert!(matches!(exceeded(CHUNK_SIZE as u64 + 1, 1000, 0), Some(Limit::CompressionRatio)));
+        assert!(matches!(exceeded(2 * CHUNK_SIZE as u64, 1 << 20, 2 * CHUNK_SIZE as u64),
+            Some(Limit::TotalSize)));
+    }
+
+    #[test]
+    fn over_limit_entry_keeps_overwritten_file() {
+        let mut data = bundle(None, &[
+            (SOURCE_NAME, b"[.image asset:x.png;][.image asset:y.png;]"),
+            ("assets/x.png", b"xxx"), ("assets/y.png", b"yyyyyyyyyy"),
+        ]);
+        // y.png claims to be a single byte, so only its actual size exceeds the limit
+        let name = b"assets/y.png";
+        for i in 0..data.len() - name.len() {
+            if &data[i..i + name.len()] != name {
+                continue;
+            }
+            if i >= 30 && data[i - 30..i - 26] == *b"PK\x03\x04" {
+                data[i - 8..i - 4].copy_from_slice(&1u32.to_le_bytes());
+            } else if i >= 46 && data[i - 46..i - 42] == *b"PK\x01\x02" {
+                data[i - 22..i - 18].copy_from_slice(&1u32.to_le_bytes());
+            }
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        fs::write(out.join("x.png"), b"old").unwrap();
+        let options = UnarchiveOptions {
+            on_collision: CollisionPolicy::Overwrite,
+            subdirectory: false,
+            limits: ExtractLimits { entry_size: 5, ..Default::default() },
+        };
+
+        let e = unarchive(&dir, &data, &options).err().unwrap();
+        assert!(matches!(ArchiveError::from(e),
+            ArchiveError::LimitExceeded { limit: Limit::EntrySize, .. }));
+        assert_eq!(fs::read(out.join("x.png")).unwrap(), b"old");
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn cleans_up_when_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = bundle(None, &[
+            (SOURCE_NAME, b"[.image asset:x.png;][.image asset:y.png;]"),
+            ("assets/x.png", b"xxx"), ("assets/y.png", b"yyy"),
+        ]);
+        let token = CancellationToken::default();
+        // cancelled as soon as progress is first reported
+        let cancel = token.clone();
+        let channel = Channel::new(move |_| { cancel.cancel(); Ok(()) });
+
+        let e = extract(&dir, &data, &UnarchiveOptions::default(), &channel, &token).err().unwrap();
+        assert!(matches!(ArchiveError::from(e), ArchiveError::Cancelled));
+        // including the subdirectory
+        assert_eq!(fs::read_dir(dir.path().join("out")).unwrap().count(), 0);
+    }
 }
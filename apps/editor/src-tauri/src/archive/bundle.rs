@@ -0,0 +1,325 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tauri::State;
+use tauri::http::{Response, StatusCode, Uri, header};
+use tauri::ipc::Channel;
+use zip::{ZipArchive, ZipWriter};
+use zip::write::SimpleFileOptions;
+
+use crate::operation::{CancellationToken, Operations};
+
+use super::manifest::{AssetRecord, Manifest, MANIFEST_NAME};
+use super::syntax;
+use super::{
+    ArchiveError, ExtractLimits, Progress, ProgressTracker, SOURCE_NAME,
+    collect_files, read_source, temp_file_beside, write_asset,
+};
+
+/// A bundle opened in place: its assets are read straight from the zip
+/// instead of being extracted to disk.
+pub struct OpenBundle {
+    pub path: PathBuf,
+    zip: ZipArchive<BufReader<File>>,
+    limits: ExtractLimits,
+}
+
+impl OpenBundle {
+    /// Opens a bundle and returns it along with its source.
+    fn open(path: PathBuf) -> anyhow::Result<(Self, String)> {
+        let mut zip = ZipArchive::new(BufReader::new(File::open(&path)?))?;
+        let limits = ExtractLimits::default();
+        limits.check_declared(&mut zip)?;
+        // validates the format version
+        Manifest::read_from(&mut zip, &limits)?;
+        let source = read_source(&mut zip, &limits, 0)?;
+        Ok((OpenBundle { path, zip, limits }, source))
+    }
+
+    /// Returns `None` if the bundle has no asset of this name.
+    pub fn read_asset(&mut self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let entry_name = Path::new("assets/").join(name).to_string_lossy().to_string();
+        let Some(index) = self.zip.index_for_name(&entry_name) else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        let entry = self.zip.by_index(index)?;
+        let compressed = entry.compressed_size();
+        entry.take(self.limits.entry_budget(compressed, 0)).read_to_end(&mut data)?;
+        self.limits.check_actual(&entry_name, data.len() as u64, compressed, 0)?;
+        Ok(Some(data))
+    }
+
+    /// Builds the manifest record of an asset in a bundle without a manifest.
+    fn legacy_record(&mut self, index: usize, name: &str) -> anyhow::Result<AssetRecord> {
+        let mut hasher = Sha256::new();
+        let entry = self.zip.by_index(index)?;
+        let compressed = entry.compressed_size();
+        let size = io::copy(&mut entry.take(self.limits.entry_budget(compressed, 0)), &mut hasher)?;
+        self.limits.check_actual(name, size, compressed, 0)?;
+        Ok(AssetRecord::new(
+            name.to_string(), vec![], size, format!("{:x}", hasher.finalize())))
+    }
+
+    /// Writes `source` back into the bundle and returns it as stored. Assets
+    /// still referenced are copied over without recompression, `file:`
+    /// references are added as new assets, and assets no longer referenced are
+    /// dropped. The bundle file is replaced atomically.
+    fn save(
+        &mut self, source: &str, channel: &Channel<Progress>, token: &CancellationToken
+    ) -> anyhow::Result<String> {
+        let (source, added) = collect_files(source, token, None)?;
+        // asset name -> (content hash, asset)
+        let mut added: HashMap<_, _> = added.into_iter()
+            .map(|(hash, asset)| (asset.name.clone(), (hash, asset)))
+            .collect();
+        let mut old_records: HashMap<_, _> = Manifest::read_from(&mut self.zip, &self.limits)?
+            .map(|m| m.assets)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|r| (r.name.clone(), r))
+            .collect();
+
+        let referenced: BTreeSet<String> = syntax::arguments(&source)
+            .into_iter()
+            .filter_map(|arg| arg.value?.strip_prefix("asset:").map(str::to_string))
+            .collect();
+
+        let bytes_total = added.iter()
+            .filter(|(name, _)| self.zip.index_for_name(&format!("assets/{name}")).is_none())
+            .map(|(_, (_, asset))| asset.size)
+            .sum();
+        let mut tracker = ProgressTracker::new(channel, token, bytes_total);
+
+        let mut temp = temp_file_beside(&self.path)?;
+        let mut zip = ZipWriter::new(BufWriter::new(temp.as_file_mut()));
+        let options = SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+
+        zip.start_file(SOURCE_NAME, options)?;
+        zip.write_all(source.as_bytes())?;
+        zip.add_directory("assets/", options)?;
+
+        let mut records = Vec::with_capacity(referenced.len());
+        for name in referenced {
+            token.check()?;
+            if let Some(index) = self.zip.index_for_name(&format!("assets/{name}")) {
+                let mut record = match old_records.remove(&name) {
+                    Some(record) => record,
+                    None => self.legacy_record(index, &name)?,
+                };
+                // a file whose content the bundle already has
+                if let Some((_, asset)) = added.remove(&name) {
+                    for path in asset.original_paths {
+                        if !record.original_paths.contains(&path) {
+                            record.original_paths.push(path);
+                        }
+                    }
+                }
+                zip.raw_copy_file(self.zip.by_index_raw(index)?)?;
+                records.push(record);
+            } else if let Some((hash, asset)) = added.remove(&name) {
+                records.push(write_asset(&mut zip, &mut tracker, options, hash, asset)?);
+            } else {
+                log::warn!("failed to resolve asset: {name}");
+            }
+        }
+
+        zip.start_file(MANIFEST_NAME, options)?;
+        serde_json::to_writer_pretty(&mut zip, &Manifest::new(records)?)?;
+        zip.finish()?.flush()?;
+
+        temp.persist(&self.path)?;
+        self.zip = ZipArchive::new(BufReader::new(File::open(&self.path)?))?;
+        Ok(source)
+    }
+}
+
+#[derive(Default)]
+pub struct Bundles {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<String, Arc<Mutex<OpenBundle>>>>,
+}
+
+impl Bundles {
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get(&self, id: &str) -> Option<Arc<Mutex<OpenBundle>>> {
+        self.open.lock().unwrap().get(id).cloned()
+    }
+
+    fn insert(&self, bundle: OpenBundle) -> String {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        self.open.lock().unwrap().insert(id.clone(), Arc::new(Mutex::new(bundle)));
+        id
+    }
+
+    fn remove(&self, id: &str) -> bool {
+        self.open.lock().unwrap().remove(id).is_some()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenedBundle {
+    id: String,
+    /// Source with `asset:` references left intact
+    source: String,
+}
+
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn open_archive(
+    path: String, bundles: State<'_, Arc<Bundles>>
+) -> Result<OpenedBundle, ArchiveError> {
+    let (bundle, source) =
+        tauri::async_runtime::spawn_blocking(move || OpenBundle::open(path.into()))
+        .await
+        .map_err(|e| ArchiveError::Failed { msg: e.to_string() })?
+        .map_err(ArchiveError::from)?;
+    let id = bundles.insert(bundle);
+    Ok(OpenedBundle { id, source })
+}
+
+/// Returns `false` if no bundle with this id is open.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn close_archive(id: String, bundles: State<'_, Arc<Bundles>>) -> bool {
+    bundles.remove(&id)
+}
+
+/// Saves an opened bundle in place; see `OpenBundle::save`.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn save_archive(
+    channel: Channel<Progress>, bundle: String, source: String, id: String,
+    bundles: State<'_, Arc<Bundles>>, operations: State<'_, Arc<Operations>>,
+) -> Result<String, ArchiveError> {
+    let Some(open) = bundles.get(&bundle) else {
+        return Err(ArchiveError::Failed { msg: format!("bundle {bundle} is not open") });
+    };
+    let operation = operations.register(id);
+    tauri::async_runtime::spawn_blocking(move || {
+        open.lock().unwrap().save(&source, &channel, operation.token())
+    })
+    .await
+    .map_err(|e| ArchiveError::Failed { msg: e.to_string() })?
+    .map_err(ArchiveError::from)
+}
+
+fn status(code: StatusCode) -> Response<Vec<u8>> {
+    Response::builder().status(code).body(Vec::new()).unwrap()
+}
+
+/// Handles the `emmm-asset` URI scheme. The path is `<bundle-id>/<name>`,
+/// percent-encoded as a whole the way `convertFileSrc` does it.
+#[allow(clippy::missing_panics_doc)]
+pub fn serve_asset(bundles: &Bundles, uri: &Uri) -> Response<Vec<u8>> {
+    let path = percent_decode_str(uri.path().trim_start_matches('/')).decode_utf8_lossy();
+    let Some((id, name)) = path.split_once('/') else {
+        return status(StatusCode::BAD_REQUEST);
+    };
+    let Some(bundle) = bundles.get(id) else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    let result = bundle.lock().unwrap().read_asset(name);
+    match result {
+        Ok(Some(data)) => Response::builder()
+            .header(header::CONTENT_TYPE,
+                mime_guess::from_path(name).first_or_octet_stream().as_ref())
+            .body(data)
+            .unwrap(),
+        Ok(None) => status(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::warn!("failed to serve asset {name} of bundle {id}: {e}");
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::test_bundles::bundle;
+    use super::super::{ArchiveOptions, asset_name, write_archive};
+    use super::*;
+
+    fn channel() -> Channel<Progress> {
+        Channel::new(|_| Ok(()))
+    }
+
+    fn asset(data: &[u8]) -> String {
+        asset_name(&format!("{:x}", Sha256::digest(data)), Some("png"))
+    }
+
+    #[test]
+    fn save_keeps_drops_and_adds_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            fs::write(&path, data).unwrap();
+            path.display().to_string()
+        };
+        let (a, b) = (file("a.png", b"aaa"), file("b.png", b"bbb"));
+        let path = dir.path().join("doc.zip");
+        write_archive(&channel(), &CancellationToken::default(),
+            &format!("[.image file:{a};][.image file:{b};]"), &path,
+            &ArchiveOptions::default()).unwrap();
+
+        let (mut bundle, source) = OpenBundle::open(path.clone()).unwrap();
+        assert_eq!(source, format!("[.image asset:{};][.image asset:{};]",
+            asset(b"aaa"), asset(b"bbb")));
+        // b is dropped, c is new, and a copy of a is the asset a already is
+        let (copy, c) = (file("copy.png", b"aaa"), file("c.png", b"ccc"));
+        let saved = bundle.save(
+            &format!("[.image asset:{};][.image file:{copy};][.image file:{c};]", asset(b"aaa")),
+            &channel(), &CancellationToken::default()).unwrap();
+        assert_eq!(saved, format!("[.image asset:{0};][.image asset:{0};][.image asset:{1};]",
+            asset(b"aaa"), asset(b"ccc")));
+
+        assert_eq!(bundle.read_asset(&asset(b"aaa")).unwrap().unwrap(), b"aaa");
+        assert_eq!(bundle.read_asset(&asset(b"ccc")).unwrap().unwrap(), b"ccc");
+        assert!(bundle.read_asset(&asset(b"bbb")).unwrap().is_none());
+        let manifest = Manifest::read_from(&mut bundle.zip, &bundle.limits).unwrap().unwrap();
+        let paths: HashMap<_, _> = manifest.assets.into_iter()
+            .map(|r| (r.name, r.original_paths))
+            .collect();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[&asset(b"aaa")], [a, copy]);
+        assert_eq!(paths[&asset(b"ccc")], [c]);
+
+        let (_, source) = OpenBundle::open(path).unwrap();
+        assert_eq!(source, saved);
+    }
+
+    #[test]
+    fn serves_percent_encoded_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("doc.zip");
+        fs::write(&path, bundle(None, &[
+            (SOURCE_NAME, b"[.image asset:a b.png;]"), ("assets/a b.png", b"aaa"),
+        ])).unwrap();
+        let bundles = Bundles::default();
+        let id = bundles.insert(OpenBundle::open(path).unwrap().0);
+
+        let serve = |path: &str| serve_asset(&bundles, &format!("emmm-asset://localhost/{path}").parse().unwrap());
+        let response = serve(&format!("{id}%2Fa%20b.png"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(response.body(), b"aaa");
+        assert_eq!(serve(&format!("{id}/a%20b.png")).status(), StatusCode::OK);
+        assert_eq!(serve(&format!("{id}%2Fmissing.png")).status(), StatusCode::NOT_FOUND);
+        assert_eq!(serve("9%2Fa%20b.png").status(), StatusCode::NOT_FOUND);
+        assert_eq!(serve("a%20b.png").status(), StatusCode::BAD_REQUEST);
+
+        assert!(bundles.remove(&id));
+        assert_eq!(serve(&format!("{id}%2Fa%20b.png")).status(), StatusCode::NOT_FOUND);
+    }
+}
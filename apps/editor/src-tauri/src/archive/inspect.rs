@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs;
+
+use serde::Serialize;
+
+use super::{ArchiveError, foreign_file_name, syntax};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferencedFile {
+    /// Path as written in the source, without `file:`
+    path: String,
+    /// Number of references to it in the source
+    count: usize,
+    /// `None` if there is no such file
+    size: Option<u64>,
+    /// Another referenced file has the same name, so one of them will be
+    /// renamed when the bundle is extracted
+    duplicate_name: bool,
+}
+
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetInspection {
+    /// In order of first reference
+    files: Vec<ReferencedFile>,
+    /// Referenced files that do not exist, which `archive` leaves as `file:`
+    /// references
+    missing: Vec<String>,
+    /// `http:` and `https:` references, which are only bundled when remote
+    /// images are fetched
+    remote: Vec<String>,
+    /// Uncompressed size of the bundle, not counting remote images. Files with
+    /// identical content are stored once, so it may turn out smaller.
+    estimated_size: u64,
+}
+
+/// Lists what `archive` would put into a bundle for `source`.
+fn inspect(source: &str) -> AssetInspection {
+    let mut result = AssetInspection {
+        estimated_size: source.len() as u64,
+        ..Default::default()
+    };
+    let mut indices = HashMap::<String, usize>::new();
+    for value in syntax::arguments(source).into_iter().filter_map(|arg| arg.value) {
+        if value.starts_with("http:") || value.starts_with("https:") {
+            if !result.remote.contains(&value) {
+                result.remote.push(value);
+            }
+            continue;
+        }
+        let Some(path) = value.strip_prefix("file:") else { continue };
+        if let Some(&i) = indices.get(path) {
+            result.files[i].count += 1;
+            continue;
+        }
+
+        let size = fs::metadata(path).ok().filter(fs::Metadata::is_file).map(|m| m.len());
+        match size {
+            Some(size) => result.estimated_size += size,
+            None => result.missing.push(path.to_string()),
+        }
+        indices.insert(path.to_string(), result.files.len());
+        result.files.push(ReferencedFile {
+            path: path.to_string(), count: 1, size, duplicate_name: false
+        });
+    }
+
+    let mut by_name = HashMap::<&str, Vec<usize>>::new();
+    for (i, file) in result.files.iter().enumerate() {
+        if file.size.is_some()
+            && let Some(name) = foreign_file_name(&file.path)
+        {
+            by_name.entry(name).or_default().push(i);
+        }
+    }
+    let duplicates: Vec<usize> = by_name.into_values()
+        .filter(|v| v.len() > 1)
+        .flatten()
+        .collect();
+    for i in duplicates {
+        result.files[i].duplicate_name = true;
+    }
+    result
+}
+
+#[tauri::command]
+pub async fn inspect_assets(source: String) -> Result<AssetInspection, ArchiveError> {
+    tauri::async_runtime::spawn_blocking(move || inspect(&source))
+        .await
+        .map_err(|e| ArchiveError::Failed { msg: e.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inspects_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::create_dir_all(&a).unwrap();
+        fs::create_dir_all(&b).unwrap();
+        fs::write(a.join("x.png"), b"12345").unwrap();
+        fs::write(b.join("x.png"), b"123").unwrap();
+        fs::write(b.join("y.png"), b"1").unwrap();
+
+        let (a, b) = (a.display(), b.display());
+        let source = format!(
+            "[.image file:{a}/x.png;]\n[.image file:{b}/x.png;]\n[.image file:{b}/y.png;]\n\
+             [.image file:{a}/x.png;]\n[.image file:{b}/missing.png;]\n\
+             [.image https://example.com/z.png;]\n[.code]\n[.image file:{b}/code.png;]\n");
+        let result = inspect(&source);
+
+        let summary: Vec<_> = result.files.iter()
+            .map(|f| (f.count, f.size, f.duplicate_name))
+            .collect();
+        assert_eq!(summary, [
+            (2, Some(5), true),
+            (1, Some(3), true),
+            (1, Some(1), false),
+            (1, None, false),
+        ]);
+        assert_eq!(result.missing, [format!("{b}/missing.png")]);
+        assert_eq!(result.remote, ["https://example.com/z.png"]);
+        assert_eq!(result.estimated_size, source.len() as u64 + 9);
+    }
+}
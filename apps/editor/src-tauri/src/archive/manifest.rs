@@ -0,0 +1,143 @@
+use std::io::{Read, Seek};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use zip::{ZipArchive, result::ZipError};
+
+use super::ExtractLimits;
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Bump this whenever the layout of a bundle changes, and teach `migrate`
+/// how to upgrade manifests written in the previous version.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub format_version: u32,
+    /// Version of kfgui that produced the bundle
+    pub app_version: String,
+    /// RFC 3339 timestamp
+    pub created: String,
+    pub assets: Vec<AssetRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetRecord {
+    /// Entry name under `assets/`, as referenced by `asset:` in the source
+    pub name: String,
+    /// Paths in the source document that referred to this content
+    pub original_paths: Vec<String>,
+    pub size: u64,
+    pub mime: String,
+    /// Lowercase hex SHA-256 of the content
+    pub sha256: String,
+}
+
+impl AssetRecord {
+    pub fn new(
+        name: String, original_paths: Vec<String>, size: u64, sha256: String
+    ) -> Self {
+        let mime = mime_guess::from_path(&name)
+            .first_or_octet_stream()
+            .to_string();
+        AssetRecord {
+            name,
+            original_paths,
+            size,
+            mime,
+            sha256,
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(assets: Vec<AssetRecord>) -> anyhow::Result<Self> {
+        Ok(Manifest {
+            format_version: FORMAT_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: OffsetDateTime::now_utc().format(&Rfc3339)?,
+            assets,
+        })
+    }
+
+    /// Reads the manifest of a bundle, within `limits`. Returns `None` for
+    /// legacy bundles that were created before manifests existed.
+    pub fn read_from<R: Read + Seek>(
+        zip: &mut ZipArchive<R>, limits: &ExtractLimits
+    ) -> anyhow::Result<Option<Self>> {
+        let entry = match zip.by_name(MANIFEST_NAME) {
+            Ok(entry) => entry,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let compressed = entry.compressed_size();
+        let mut data = Vec::new();
+        entry.take(limits.entry_budget(compressed, 0)).read_to_end(&mut data)?;
+        limits.check_actual(MANIFEST_NAME, data.len() as u64, compressed, 0)?;
+        let value: Value = serde_json::from_slice(&data)?;
+        let version = value.get("formatVersion")
+            .and_then(Value::as_u64)
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow!("manifest has no valid format version"))?;
+        if version > FORMAT_VERSION {
+            bail!("bundle format version {version} is newer than supported \
+                   ({FORMAT_VERSION}); please update kfgui");
+        }
+        Ok(Some(serde_json::from_value(migrate(value, version)?)?))
+    }
+}
+
+/// Upgrades a manifest written in format `version` to `FORMAT_VERSION`.
+fn migrate(value: Value, version: u32) -> anyhow::Result<Value> {
+    match version {
+        FORMAT_VERSION => Ok(value),
+        v => bail!("unsupported bundle format version {v}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::super::test_bundles::{bundle, record};
+    use super::*;
+
+    fn read(data: &[u8]) -> anyhow::Result<Option<Manifest>> {
+        Manifest::read_from(&mut ZipArchive::new(Cursor::new(data))?, &ExtractLimits::default())
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = bundle(Some(vec![record("a.png", b"aaa"), record("b.JPG", b"bbb")]),
+            &[("source.emmm", b"[.image asset:a.png;]")]);
+        let manifest = read(&data).unwrap().unwrap();
+        assert_eq!(manifest.format_version, FORMAT_VERSION);
+        assert_eq!(manifest.app_version, env!("CARGO_PKG_VERSION"));
+        let [a, b] = &manifest.assets[..] else { panic!("two assets expected") };
+        assert_eq!(a.name, "a.png");
+        assert_eq!(a.original_paths, ["/photos/a.png"]);
+        assert_eq!((a.size, a.mime.as_str()), (3, "image/png"));
+        assert_eq!(a.sha256, "9834876dcfb05cb167a5c24953eba58c4ac89b1adf57f28f2f9d09af107ee8f0");
+        assert_eq!(b.mime, "image/jpeg");
+    }
+
+    #[test]
+    fn legacy_bundle_has_no_manifest() {
+        let data = bundle(None, &[("source.emmm", b"[.image asset:a.png;]"), ("assets/a.png", b"aaa")]);
+        assert!(read(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_newer_and_invalid_versions() {
+        let newer = format!(r#"{{"formatVersion": {}}}"#, FORMAT_VERSION + 1);
+        let e = read(&bundle(None, &[(MANIFEST_NAME, newer.as_bytes())])).err().unwrap();
+        assert!(e.to_string().contains("newer than supported"), "{e}");
+        assert!(read(&bundle(None, &[(MANIFEST_NAME, br#"{"formatVersion": "1"}"#)])).is_err());
+        assert!(read(&bundle(None, &[(MANIFEST_NAME, b"{")])).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, ImageFormat};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tauri::ipc::Channel;
+use tempfile::NamedTempFile;
+
+use crate::compress::{self, CompressOptions, CompressedImage};
+use crate::operation::CancellationToken;
+
+use super::{Asset, Progress, ProgressTracker, asset_name};
+
+/// Types an image may keep when it already fits.
+const KEPT_TYPES: [&str; 2] = ["image/jpeg", "image/png"];
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RecompressOptions {
+    /// Wider images are scaled down to this width
+    pub max_width: Option<usize>,
+    /// Budget for each image, in bytes
+    pub max_size: usize,
+    /// Keep the artist and copyright notice in the EXIF data
+    #[serde(default)]
+    pub keep_copyright: bool,
+}
+
+/// Result of passing the image assets through the compression pipeline.
+#[derive(Default)]
+pub struct Recompression {
+    /// Keeps the recompressed images on disk until they are written
+    pub files: Vec<NamedTempFile>,
+    /// Old asset name -> new asset name
+    pub renamed: HashMap<String, String>,
+    pub bytes_saved: u64,
+}
+
+/// Whether the pipeline can reduce an asset without losing anything that the
+/// article would show: vector images are left alone, and so are animations,
+/// which `recompress` finds once it has read them.
+fn recompressible(name: &str) -> bool {
+    mime_guess::from_path(name).first().is_some_and(|m| {
+        m.type_() == mime_guess::mime::IMAGE && m.subtype() != "svg"
+    })
+}
+
+/// Whether an image has more than one frame; the pipeline keeps only the first.
+fn is_animation(data: &[u8]) -> bool {
+    match image::guess_format(data) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(data))
+            .is_ok_and(|d| d.into_frames().nth(1).is_some()),
+        Ok(ImageFormat::WebP) => WebPDecoder::new(Cursor::new(data))
+            .is_ok_and(|d| d.has_animation()),
+        Ok(ImageFormat::Png) => PngDecoder::new(Cursor::new(data))
+            .is_ok_and(|d| d.is_apng().unwrap_or(false)),
+        _ => false,
+    }
+}
+
+/// Keeps the extension of the original when the format stays the same, so
+/// that `.jpeg` files do not turn into `.jpg` for no reason.
+fn extension<'a>(name: &'a str, image: &CompressedImage) -> &'a str {
+    let ext = Path::new(name).extension().and_then(|e| e.to_str());
+    match ext {
+        Some(ext) if mime_guess::from_ext(ext).iter()
+            .any(|m| m.essence_str() == image.mime) => ext,
+        _ => image.ext,
+    }
+}
+
+/// Replaces each image asset by its recompressed version, but only where that
+/// is smaller. Assets are renamed after their new content.
+pub fn recompress(
+    assets: &mut BTreeMap<String, Asset>, options: &RecompressOptions,
+    channel: &Channel<Progress>, token: &CancellationToken,
+) -> anyhow::Result<Recompression> {
+    let bytes_total = assets.values()
+        .filter(|a| recompressible(&a.name))
+        .map(|a| a.size)
+        .sum();
+    let mut tracker = ProgressTracker::new(channel, token, bytes_total);
+    let mut result = Recompression::default();
+
+    for (hash, asset) in std::mem::take(assets) {
+        token.check()?;
+        if !recompressible(&asset.name) {
+            assets.insert(hash, asset);
+            continue;
+        }
+        tracker.report(Some(&asset.name))?;
+        let original = fs::read(&asset.local)?;
+        if is_animation(&original) {
+            tracker.bytes_done += asset.size;
+            assets.insert(hash, asset);
+            continue;
+        }
+        let image = compress::compress(&original, &CompressOptions {
+            max_size: options.max_size,
+            max_width: options.max_width,
+            supported_types: KEPT_TYPES.map(str::to_string).to_vec(),
+            keep_copyright: options.keep_copyright,
+            min_quality: compress::DEFAULT_MIN_QUALITY,
+            max_iterations: compress::DEFAULT_MAX_ITERATIONS,
+            first_frame: false,
+            background: None,
+            palette: false,
+        });
+        tracker.bytes_done += asset.size;
+
+        let image = match image {
+            Ok(image) if (image.data.len() as u64) < asset.size => image,
+            Ok(_) => {
+                assets.insert(hash, asset);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("failed to recompress {}: {e}", asset.original_paths[0]);
+                assets.insert(hash, asset);
+                continue;
+            }
+        };
+
+        let new_hash = format!("{:x}", Sha256::digest(&image.data));
+        if let Some(existing) = assets.get_mut(&new_hash) {
+            // two images that compressed to the same content
+            existing.original_paths.extend(asset.original_paths);
+            result.renamed.insert(asset.name, existing.name.clone());
+            result.bytes_saved += asset.size;
+            continue;
+        }
+        let new_name = asset_name(&new_hash, Some(extension(&asset.name, &image)));
+        let size = image.data.len() as u64;
+        result.bytes_saved += asset.size - size;
+        result.renamed.insert(asset.name, new_name.clone());
+
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&image.data)?;
+        assets.insert(new_hash, Asset {
+            name: new_name,
+            original_paths: asset.original_paths,
+            local: file.path().to_path_buf(),
+            size,
+        });
+        result.files.push(file);
+    }
+    tracker.report(None)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use gif::Repeat;
+    use image::{Rgba, RgbaImage};
+    use tempfile::TempDir;
+
+    use crate::compress::test_images::{animated_gif, gradient, noise};
+
+    use super::*;
+
+    /// Stores `data` in `dir` as an asset named after its content.
+    fn asset(dir: &TempDir, ext: &str, data: &[u8]) -> (String, Asset) {
+        let hash = format!("{:x}", Sha256::digest(data));
+        let name = asset_name(&hash, Some(ext));
+        let local = dir.path().join(&name);
+        fs::write(&local, data).unwrap();
+        let original_paths = vec![format!("/photos/{name}")];
+        (hash, Asset { name, original_paths, local, size: data.len() as u64 })
+    }
+
+    fn png(img: &RgbaImage) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    /// An opaque image that PNG cannot compress.
+    fn noisy_png() -> Vec<u8> {
+        let mut next = noise();
+        png(&RgbaImage::from_fn(96, 96, |_, _| {
+            let [r, g, b, _] = next().to_le_bytes();
+            Rgba([r, g, b, 255])
+        }))
+    }
+
+    fn run(assets: &mut BTreeMap<String, Asset>, max_size: usize) -> Recompression {
+        let options = RecompressOptions { max_width: None, max_size, keep_copyright: false };
+        recompress(assets, &options, &Channel::new(|_| Ok(())), &CancellationToken::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn replaces_images_only_when_smaller() {
+        let dir = tempfile::tempdir().unwrap();
+        let noisy = noisy_png();
+        let (noisy_hash, noisy_asset) = asset(&dir, "png", &noisy);
+        let (small_hash, small_asset) = asset(&dir, "png", &png(&gradient(8, 8, 0)));
+        let (noisy_name, small_name) = (noisy_asset.name.clone(), small_asset.name.clone());
+        let mut assets = BTreeMap::from([
+            (noisy_hash.clone(), noisy_asset), (small_hash.clone(), small_asset),
+        ]);
+        let result = run(&mut assets, 8000);
+
+        // already fits, so compressing it gains nothing
+        assert_eq!(assets[&small_hash].name, small_name);
+        assert!(!result.renamed.contains_key(&small_name));
+
+        // renamed after its new content, in its new format
+        let new_name = &result.renamed[&noisy_name];
+        assert!(!assets.contains_key(&noisy_hash));
+        let (new_hash, new_asset) = assets.iter().find(|(_, a)| &a.name == new_name).unwrap();
+        let data = fs::read(&new_asset.local).unwrap();
+        assert_eq!(*new_hash, format!("{:x}", Sha256::digest(&data)));
+        assert_eq!(*new_name, asset_name(new_hash, Some("jpg")));
+        assert_eq!(new_asset.size, data.len() as u64);
+        assert!(data.len() < 8000);
+        assert_eq!(new_asset.original_paths, [format!("/photos/{noisy_name}")]);
+
+        assert_eq!(result.bytes_saved, noisy.len() as u64 - new_asset.size);
+        assert_eq!(result.files.len(), 1);
+    }
+
+    #[test]
+    fn leaves_vector_images_and_animations_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut assets = BTreeMap::from([
+            asset(&dir, "svg", br#"<svg xmlns="http://www.w3.org/2000/svg"/>"#),
+            asset(&dir, "gif", &animated_gif((0..3).map(|i| gradient(64, 64, i)), Repeat::Infinite)),
+        ]);
+        let before: Vec<_> = assets.iter()
+            .map(|(hash, a)| (hash.clone(), a.name.clone(), a.local.clone()))
+            .collect();
+        // would not fit otherwise
+        let result = run(&mut assets, 100);
+
+        assert!(result.renamed.is_empty());
+        assert_eq!(result.bytes_saved, 0);
+        let after: Vec<_> = assets.iter()
+            .map(|(hash, a)| (hash.clone(), a.name.clone(), a.local.clone()))
+            .collect();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn finds_animations() {
+        assert!(is_animation(&animated_gif((0..2).map(|i| gradient(8, 8, i)), Repeat::Infinite)));
+        assert!(!is_animation(&animated_gif([gradient(8, 8, 0)], Repeat::Infinite)));
+        assert!(!is_animation(&png(&gradient(8, 8, 0))));
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri_plugin_http::reqwest::{Client, header};
+use tempfile::NamedTempFile;
+
+use crate::operation::CancellationToken;
+
+/// How remote images are fetched while archiving.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RemoteOptions {
+    /// Whether `http:` and `https:` images are bundled at all
+    pub fetch: bool,
+    /// Seconds a single download may take, connecting included
+    pub timeout: u64,
+    /// Largest image accepted, in bytes
+    pub max_size: u64,
+}
+
+impl Default for RemoteOptions {
+    fn default() -> Self {
+        RemoteOptions { fetch: false, timeout: 30, max_size: 50 << 20 }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedDownload {
+    pub url: String,
+    pub reason: String,
+}
+
+/// A remote image saved to a temporary file.
+#[derive(Debug)]
+pub struct Download {
+    pub file: NamedTempFile,
+    pub hash: String,
+    pub size: u64,
+    /// Extension to name the asset with
+    pub ext: String,
+}
+
+/// Downloads remote images for one archive, keeping them on disk until the
+/// archive has been written.
+pub struct Fetcher {
+    client: Client,
+    max_size: u64,
+    pub downloads: Vec<NamedTempFile>,
+    pub failed: Vec<FailedDownload>,
+}
+
+impl Fetcher {
+    pub fn new(options: &RemoteOptions) -> anyhow::Result<Self> {
+        let timeout = Duration::from_secs(options.timeout);
+        let client = Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()?;
+        Ok(Fetcher {
+            client,
+            max_size: options.max_size,
+            downloads: Vec::new(),
+            failed: Vec::new(),
+        })
+    }
+
+    pub fn has_failed(&self, url: &str) -> bool {
+        self.failed.iter().any(|f| f.url == url)
+    }
+
+    /// Downloads `url` if it is an image. Returns `Ok(None)` for anything else,
+    /// such as a web page given to a link.
+    pub fn download(
+        &self, url: &str, token: &CancellationToken
+    ) -> anyhow::Result<Option<Download>> {
+        tauri::async_runtime::block_on(async {
+            let mut response = self.client.get(url).send().await?.error_for_status()?;
+            let mime = response.headers().get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<mime_guess::Mime>().ok());
+            let Some(mime) = mime.filter(|m| m.type_() == mime_guess::mime::IMAGE) else {
+                log::debug!("not an image: {url}");
+                return Ok(None);
+            };
+            if let Some(len) = response.content_length()
+                && len > self.max_size
+            {
+                bail!("image is {len} bytes, larger than the limit of {}", self.max_size);
+            }
+
+            let mut file = NamedTempFile::new()?;
+            let mut hasher = Sha256::new();
+            let mut size = 0;
+            while let Some(chunk) = response.chunk().await? {
+                token.check()?;
+                size += chunk.len() as u64;
+                if size > self.max_size {
+                    bail!("image is larger than the limit of {}", self.max_size);
+                }
+                hasher.update(&chunk);
+                file.write_all(&chunk)?;
+            }
+            file.flush()?;
+            Ok(Some(Download {
+                file,
+                hash: format!("{:x}", hasher.finalize()),
+                size,
+                ext: extension(url, &mime),
+            }))
+        })
+    }
+}
+
+/// Prefers the extension in the URL, as long as it agrees with the content
+/// type that the server sent.
+fn extension(url: &str, mime: &mime_guess::Mime) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let name = path.rsplit('/').next().unwrap_or_default();
+    if let Some((_, ext)) = name.rsplit_once('.')
+        && mime_guess::from_ext(ext).iter().any(|m| m.essence_str() == mime.essence_str())
+    {
+        return ext.to_lowercase();
+    }
+    // image/svg+xml -> svg
+    let subtype = mime.subtype().as_str();
+    subtype.split('+').next().unwrap_or(subtype).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::test_server::{self, response};
+
+    use super::*;
+
+    fn serve(response: Vec<u8>) -> String {
+        test_server::serve(response, "/images/photo.JPG?size=large")
+    }
+
+    fn fetcher(timeout: u64, max_size: u64) -> Fetcher {
+        Fetcher::new(&RemoteOptions { fetch: true, timeout, max_size }).unwrap()
+    }
+
+    #[test]
+    fn downloads_images() {
+        let url = serve(response("200 OK", "image/jpeg", b"not really a jpeg"));
+        let download = fetcher(10, 1000)
+            .download(&url, &CancellationToken::default()).unwrap().unwrap();
+        assert_eq!(download.size, 17);
+        assert_eq!(download.ext, "jpg");
+        assert_eq!(download.hash, format!("{:x}", Sha256::digest(b"not really a jpeg")));
+        assert_eq!(std::fs::read(download.file.path()).unwrap(), b"not really a jpeg");
+    }
+
+    #[test]
+    fn extension_follows_content_type() {
+        let url = serve(response("200 OK", "image/png", b"x"));
+        let download = fetcher(10, 1000)
+            .download(&url, &CancellationToken::default()).unwrap().unwrap();
+        assert_eq!(download.ext, "png");
+    }
+
+    #[test]
+    fn skips_other_content() {
+        let url = serve(response("200 OK", "text/html", b"<html></html>"));
+        let result = fetcher(10, 1000).download(&url, &CancellationToken::default());
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn fails_on_error_status() {
+        let url = serve(response("404 Not Found", "image/png", b""));
+        let result = fetcher(10, 1000).download(&url, &CancellationToken::default());
+        assert!(result.unwrap_err().to_string().contains("404"));
+    }
+
+    #[test]
+    fn enforces_size_limit() {
+        let url = serve(response("200 OK", "image/png", &[0; 2000]));
+        let result = fetcher(10, 1000).download(&url, &CancellationToken::default());
+        assert!(result.unwrap_err().to_string().contains("limit"));
+
+        // without a declared length, the limit applies while streaming
+        let mut r = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\
+                      Connection: close\r\n\r\n".to_vec();
+        r.extend_from_slice(&[0; 2000]);
+        let url = serve(r);
+        let result = fetcher(10, 1000).download(&url, &CancellationToken::default());
+        assert!(result.unwrap_err().to_string().contains("limit"));
+    }
+
+    #[test]
+    fn times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/a.png", listener.local_addr().unwrap());
+        let result = fetcher(1, 1000).download(&url, &CancellationToken::default());
+        assert!(result.is_err());
+        drop(listener);
+    }
+}
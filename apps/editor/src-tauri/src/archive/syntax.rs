@@ -0,0 +1,431 @@
+//! Just enough of the emmm grammar (see `packages/libemmm/src/parser.ts`) to
+//! find the arguments of modifiers, so that references in them can be
+//! rewritten without touching escaped text, code blocks or anything else that
+//! merely looks like a reference.
+//!
+//! Shorthands are defined by the document itself and are not recognized, and
+//! neither are modifiers defined anywhere but in libemmm's built-in and
+//! default sets: their names are read the way the parser reads unknown ones.
+
+use std::ops::Range;
+
+const ESCAPE_CHAR: char = '\\';
+const MODIFIER_OPENS: [&str; 3] = ["[.", "[/", "[-"];
+const MODIFIER_INLINE_OPEN: &str = "[/";
+const MODIFIER_SYSTEM_OPEN: &str = "[-";
+const MODIFIER_CLOSE_SIGN: char = ']';
+const MODIFIER_END_SIGN: char = ';';
+const MODIFIER_INLINE_END_TAG: &str = "[;]";
+const MODIFIER_ARGUMENT_SEPARATOR: char = '|';
+const INTERPOLATION_OPEN: &str = "$(";
+const INTERPOLATION_CLOSE: char = ')';
+const GROUP_BEGIN: &str = "<<<";
+const GROUP_END: &str = ">>>";
+
+const BLOCK_MODIFIERS: [&str; 31] = [
+    "break", "bullet-item", "by", "callout", "code", "concat", "detail", "epitaph",
+    "gallery", "heading", "ifdef", "ifndef", "image", "implicit-heading",
+    "inject-pre-slot", "link", "module", "note", "numbered-heading", "ordered-item",
+    "pre-slot", "quote", "raw", "slot", "style", "subitem", "table", "table-cell",
+    "table-row", "table-separator", "use",
+];
+const INLINE_MODIFIERS: [&str; 19] = [
+    "$", "code", "commentary", "emphasis", "highlight", "ifdef", "ifndef",
+    "inject-pre-slot", "keyword", "link", "note", "note-inline", "pre-slot", "print",
+    "ruby", "seq", "slot", "tab", "table-cell",
+];
+const SYSTEM_MODIFIERS: [&str; 8] = [
+    "block-shorthand", "define-block", "define-inline", "inline-shorthand",
+    "note-position", "note-renumbering", "use", "var",
+];
+
+/// Built-in modifiers whose content is taken verbatim.
+const PREFORMATTED: [&str; 2] = ["code", "raw"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Argument {
+    /// Where the value is in the source. For a named argument this excludes
+    /// the `name=` part.
+    pub span: Range<usize>,
+    /// The value with escapes resolved, or `None` if it contains an
+    /// interpolation and so is only known once the document is expanded.
+    pub value: Option<String>,
+}
+
+/// Lists the arguments of all modifiers in `source`, in order.
+pub fn arguments(source: &str) -> Vec<Argument> {
+    let mut scanner = Scanner { src: source, pos: 0, args: Vec::new() };
+    scanner.document();
+    scanner.args
+}
+
+/// Replaces each argument for which `f` returns a new value, leaving the rest
+/// of the source exactly as it was.
+pub fn rewrite_arguments(
+    source: &str, mut f: impl FnMut(&str) -> Option<String>
+) -> String {
+    let mut result = String::with_capacity(source.len());
+    let mut last = 0;
+    for arg in arguments(source) {
+        if let Some(value) = arg.value.as_deref().and_then(&mut f) {
+            result.push_str(&source[last..arg.span.start]);
+            result.push_str(&escape(&value));
+            last = arg.span.end;
+        }
+    }
+    result.push_str(&source[last..]);
+    result
+}
+
+/// Escapes `value` so that it reads back as a single argument.
+pub fn escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ESCAPE_CHAR | MODIFIER_ARGUMENT_SEPARATOR
+            | MODIFIER_END_SIGN | MODIFIER_CLOSE_SIGN | '$')
+        {
+            result.push(ESCAPE_CHAR);
+        }
+        result.push(c);
+    }
+    result
+}
+
+struct Scanner<'a> {
+    src: &'a str,
+    pos: usize,
+    args: Vec<Argument>,
+}
+
+impl Scanner<'_> {
+    fn is_eof(&self) -> bool {
+        self.pos >= self.src.len()
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self, s: &str) -> bool {
+        self.rest().starts_with(s)
+    }
+
+    fn peek_char(&self, c: char) -> bool {
+        self.rest().starts_with(c)
+    }
+
+    fn accept(&mut self, s: &str) -> bool {
+        let ok = self.peek(s);
+        if ok { self.pos += s.len(); }
+        ok
+    }
+
+    fn accept_char(&mut self, c: char) -> bool {
+        let ok = self.peek_char(c);
+        if ok { self.pos += c.len_utf8(); }
+        ok
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.rest().chars().next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn whitespaces(&mut self) {
+        while self.accept_char(' ') || self.accept_char('\t') {}
+    }
+
+    fn whitespaces_or_newlines(&mut self) {
+        while self.accept_char(' ') || self.accept_char('\t') || self.accept_char('\n') {}
+    }
+
+    fn peek_modifier(&self) -> bool {
+        MODIFIER_OPENS.iter().any(|x| self.peek(x))
+    }
+
+    fn document(&mut self) {
+        while !self.is_eof() {
+            if self.accept_char(ESCAPE_CHAR) {
+                self.next_char();
+            } else if self.peek_modifier() {
+                self.modifier();
+            } else {
+                self.next_char();
+            }
+        }
+    }
+
+    fn modifier(&mut self) {
+        let inline = self.peek(MODIFIER_INLINE_OPEN);
+        let names: &[&str] = if inline {
+            &INLINE_MODIFIERS
+        } else if self.peek(MODIFIER_SYSTEM_OPEN) {
+            &SYSTEM_MODIFIERS
+        } else {
+            &BLOCK_MODIFIERS
+        };
+        self.pos += 2;
+
+        // the parser takes the longest defined name the head starts with
+        let name = names.iter().filter(|x| self.peek(x)).max_by_key(|x| x.len());
+        if let Some(name) = name {
+            self.pos += name.len();
+        } else {
+            // an unknown name ends at a whitespace, which is skipped, or at
+            // the end of the head; a separator is part of it
+            while !self.is_eof()
+                && !self.accept_char(' ') && !self.accept_char('\t')
+                && !self.peek_char(MODIFIER_CLOSE_SIGN)
+                && !self.peek_char(MODIFIER_END_SIGN)
+            {
+                self.accept_char(ESCAPE_CHAR);
+                self.next_char();
+            }
+        }
+        let preformatted = name.is_some_and(|x| PREFORMATTED.contains(x));
+
+        self.arguments();
+        let marker = self.accept_char(MODIFIER_END_SIGN);
+        self.accept_char(MODIFIER_CLOSE_SIGN);
+        if marker || !preformatted {
+            return;
+        }
+        if inline {
+            self.preformatted_inline();
+        } else {
+            self.preformatted_block();
+        }
+    }
+
+    fn arguments(&mut self) {
+        if !self.accept_char(MODIFIER_ARGUMENT_SEPARATOR) {
+            self.whitespaces_or_newlines();
+        }
+        if self.peek_char(MODIFIER_CLOSE_SIGN) || self.peek_char(MODIFIER_END_SIGN) {
+            return;
+        }
+        while self.argument() {}
+    }
+
+    /// Returns `false` after the last argument.
+    fn argument(&mut self) -> bool {
+        let mut start = self.pos;
+        let mut end = self.pos;
+        let mut value = Some(String::new());
+        // a named argument starts with an unescaped `name=`
+        let mut possibly_named = true;
+
+        let more = loop {
+            if self.accept_char(MODIFIER_ARGUMENT_SEPARATOR) {
+                break true;
+            }
+            if self.is_eof()
+                || self.peek_char(MODIFIER_END_SIGN)
+                || self.peek_char(MODIFIER_CLOSE_SIGN)
+            {
+                break false;
+            }
+            if self.accept_char(ESCAPE_CHAR) {
+                possibly_named = false;
+                let Some(c) = self.next_char() else {
+                    if let Some(v) = &mut value { v.push(ESCAPE_CHAR); }
+                    end = self.pos;
+                    break false;
+                };
+                if let Some(v) = &mut value { v.push(c); }
+                end = self.pos;
+                continue;
+            }
+            if self.accept(INTERPOLATION_OPEN) {
+                possibly_named = false;
+                value = None;
+                let closed = self.interpolation();
+                end = self.pos;
+                if !closed { break false; }
+                continue;
+            }
+
+            let c = self.next_char().unwrap_or_default();
+            end = self.pos;
+            if possibly_named {
+                if c == '=' {
+                    possibly_named = false;
+                    start = self.pos;
+                    value = Some(String::new());
+                    continue;
+                }
+                if matches!(c, ':' | '/' | '[') || c.is_whitespace() {
+                    possibly_named = false;
+                }
+            }
+            if let Some(v) = &mut value { v.push(c); }
+        };
+        self.args.push(Argument { span: start..end, value });
+        more
+    }
+
+    /// Skips the rest of an interpolation. Returns `false` if it is unclosed.
+    fn interpolation(&mut self) -> bool {
+        loop {
+            if self.accept_char(INTERPOLATION_CLOSE) {
+                return true;
+            }
+            if self.is_eof()
+                || self.peek_char(MODIFIER_END_SIGN)
+                || self.peek_char(MODIFIER_CLOSE_SIGN)
+            {
+                return false;
+            }
+            if self.accept_char(ESCAPE_CHAR) {
+                if self.next_char().is_none() { return false; }
+            } else if self.accept(INTERPOLATION_OPEN) {
+                if !self.interpolation() { return false; }
+            } else {
+                self.next_char();
+            }
+        }
+    }
+
+    /// Skips the content of a preformatted block modifier: a paragraph, or a
+    /// group delimited by `<<<` and `>>>`.
+    fn preformatted_block(&mut self) {
+        self.whitespaces_or_newlines();
+        let grouped = self.accept(GROUP_BEGIN);
+        while !self.is_eof() {
+            if self.accept_char('\n') {
+                self.whitespaces();
+                if grouped && self.accept(GROUP_END) { break; }
+                if !grouped && self.accept_char('\n') { break; }
+            } else {
+                self.next_char();
+            }
+        }
+    }
+
+    /// Skips the content of a preformatted inline modifier, which ends at its
+    /// end tag or with the paragraph.
+    fn preformatted_inline(&mut self) {
+        while !self.is_eof() {
+            if self.accept(MODIFIER_INLINE_END_TAG) {
+                break;
+            }
+            if self.accept_char('\n') {
+                self.whitespaces();
+                if self.peek("[.") || self.peek("[-")
+                    || self.peek(GROUP_END) || self.peek_char('\n')
+                {
+                    break;
+                }
+            } else {
+                self.next_char();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(source: &str) -> Vec<Option<String>> {
+        arguments(source).into_iter().map(|a| a.value).collect()
+    }
+
+    fn strings(values: &[&str]) -> Vec<Option<String>> {
+        values.iter().map(|v| Some((*v).to_string())).collect()
+    }
+
+    #[test]
+    fn simple_arguments() {
+        assert_eq!(values("[.image file:/a.png;]"), strings(&["file:/a.png"]));
+        assert_eq!(values("[/link a|b]text[;]"), strings(&["a", "b"]));
+        assert_eq!(values("[.image|file:/a.png]\nx"), strings(&["file:/a.png"]));
+        assert_eq!(values("[.quote]\ntext"), strings(&[]));
+        assert_eq!(values("[.image file:/a.png"), strings(&["file:/a.png"]));
+    }
+
+    #[test]
+    fn unknown_names_end_like_in_the_parser() {
+        // the "unknown modifiers" case of libemmm's basic syntax tests
+        assert_eq!(values("[.invalid]aaa[/invalid]bbb[;]ccc"), strings(&[]));
+        assert_eq!(values("[.invalid|file:/a.png]\nx"), strings(&[]));
+        assert_eq!(values("[.invalid |file:/a.png]\nx"), strings(&["file:/a.png"]));
+        assert_eq!(values(r"[/in\ valid file:/a.png]x[;]"), strings(&["file:/a.png"]));
+        // a known name is matched as a prefix
+        assert_eq!(values("[.imagefile:/a.png;]"), strings(&["file:/a.png"]));
+    }
+
+    #[test]
+    fn named_arguments_exclude_the_name() {
+        let source = "[.image url=file:/a.png|x=1;]";
+        let args = arguments(source);
+        assert_eq!(&source[args[0].span.clone()], "file:/a.png");
+        assert_eq!(args[1].value.as_deref(), Some("1"));
+        // a colon before the equals sign means it's not a name
+        assert_eq!(values("[.image file:/a=b.png;]"), strings(&["file:/a=b.png"]));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            values(r"[.image file:/a\;b\]c\|d\\e.png;]"),
+            strings(&[r"file:/a;b]c|d\e.png"]));
+        // escaped modifier openings are plain text
+        assert_eq!(values(r"\[.image file:/a.png;]"), strings(&[]));
+        assert_eq!(values(r"\\[.image file:/a.png;]"), strings(&["file:/a.png"]));
+    }
+
+    #[test]
+    fn interpolations_have_no_static_value() {
+        assert_eq!(values("[.image file:$(dir)/a.png|b;]"), vec![None, Some("b".into())]);
+        assert_eq!(values("[.image $(a|$(b;c)) ;]"), vec![None]);
+    }
+
+    #[test]
+    fn text_is_not_an_argument() {
+        assert_eq!(values("see file:/a.png; and [/emph file:/b.png]!"), strings(&["file:/b.png"]));
+    }
+
+    #[test]
+    fn preformatted_content_is_skipped() {
+        let source = "[.code]\n[.image file:/a.png;]\n\n[.image file:/b.png;]";
+        assert_eq!(values(source), strings(&["file:/b.png"]));
+
+        let source = "[.code]\n<<<\n[.image file:/a.png;]\n\n[.image file:/b.png;]\n>>>\n\
+                      [.image file:/c.png;]";
+        assert_eq!(values(source), strings(&["file:/c.png"]));
+
+        let source = "[/code][/link file:/a.png]x[;][;] [/link file:/b.png]y[;]";
+        assert_eq!(values(source), strings(&["file:/b.png"]));
+
+        // an argument-less code marker has no content
+        let source = "[.code;]\n[.image file:/a.png;]";
+        assert_eq!(values(source), strings(&["file:/a.png"]));
+    }
+
+    #[test]
+    fn rewrite_touches_only_arguments() {
+        let source = "file:/a.png [.image file:/a.png;] \\[.image file:/a.png;]\n\
+                      [.code]\n[.image file:/a.png;]\n\n[.image|x|url=file:/a.png]";
+        let result = rewrite_arguments(source, |v| {
+            v.strip_prefix("file:").map(|p| format!("asset:{}", p.len()))
+        });
+        assert_eq!(result, "file:/a.png [.image asset:6;] \\[.image file:/a.png;]\n\
+                            [.code]\n[.image file:/a.png;]\n\n[.image|x|url=asset:6]");
+    }
+
+    #[test]
+    fn rewrite_escapes_values() {
+        let result = rewrite_arguments("[.image file:x;]", |_| Some("file:/a;b]$(c)|d".into()));
+        assert_eq!(result, r"[.image file:/a\;b\]\$(c)\|d;]");
+        assert_eq!(values(&result), strings(&["file:/a;b]$(c)|d"]));
+    }
+
+    #[test]
+    fn unicode() {
+        let source = "[.image file:/图片/ä.png;]ü";
+        let args = arguments(source);
+        assert_eq!(&source[args[0].span.clone()], "file:/图片/ä.png");
+    }
+}
@@ -0,0 +1,29 @@
+use std::io::{Cursor, Write};
+
+use sha2::{Digest, Sha256};
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+use super::manifest::{AssetRecord, Manifest, MANIFEST_NAME};
+
+pub fn record(name: &str, data: &[u8]) -> AssetRecord {
+    AssetRecord::new(
+        name.to_string(), vec![format!("/photos/{name}")], data.len() as u64,
+        format!("{:x}", Sha256::digest(data)))
+}
+
+/// Builds an uncompressed bundle, so that tests can tamper with its bytes.
+pub fn bundle(records: Option<Vec<AssetRecord>>, entries: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored);
+    for (name, data) in entries {
+        zip.start_file(*name, options).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    if let Some(records) = records {
+        zip.start_file(MANIFEST_NAME, options).unwrap();
+        serde_json::to_writer(&mut zip, &Manifest::new(records).unwrap()).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
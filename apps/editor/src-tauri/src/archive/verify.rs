@@ -0,0 +1,241 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tauri::State;
+use tauri::ipc::Channel;
+use zip::ZipArchive;
+
+use crate::operation::{Cancelled, CancellationToken, Operations};
+
+use super::manifest::{AssetRecord, Manifest, MANIFEST_NAME};
+use super::syntax;
+use super::{ArchiveError, ExtractLimits, Progress, ProgressTracker, SOURCE_NAME, read_source};
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", tag = "kind", content = "data")]
+pub enum Corruption {
+    /// The entry cannot be decompressed or fails its CRC check
+    #[serde(rename_all = "camelCase")]
+    Unreadable { msg: String },
+    #[serde(rename_all = "camelCase")]
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The content differs from what the manifest recorded
+    HashMismatch,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CorruptedEntry {
+    entry: String,
+    corruption: Corruption,
+}
+
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    /// Legacy bundles have no manifest, so their assets can only be checked
+    /// against the CRCs in the zip
+    has_manifest: bool,
+    /// Entries the bundle should have but lacks
+    missing: Vec<String>,
+    /// Entries that the manifest does not account for
+    extra: Vec<String>,
+    corrupted: Vec<CorruptedEntry>,
+    /// Assets referred to by the source that the bundle lacks
+    unresolved: Vec<String>,
+}
+
+impl VerifyReport {
+    fn corrupted(&mut self, entry: &str, corruption: Corruption) {
+        self.corrupted.push(CorruptedEntry { entry: entry.to_string(), corruption });
+    }
+
+    fn check_record(&mut self, record: &AssetRecord, size: u64, hash: &str) {
+        let entry = format!("assets/{}", record.name);
+        if size != record.size {
+            self.corrupted(&entry, Corruption::SizeMismatch { expected: record.size, actual: size });
+        } else if hash != record.sha256 {
+            self.corrupted(&entry, Corruption::HashMismatch);
+        }
+    }
+}
+
+/// Reads every entry of a bundle once, without extracting anything.
+fn verify<R: Read + Seek>(
+    zip: &mut ZipArchive<R>, channel: &Channel<Progress>, token: &CancellationToken
+) -> anyhow::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let manifest = Manifest::read_from(zip, &ExtractLimits::default()).unwrap_or_else(|e| {
+        report.corrupted(MANIFEST_NAME, Corruption::Unreadable { msg: e.to_string() });
+        None
+    });
+    report.has_manifest = manifest.is_some();
+    // entry name -> record
+    let mut expected: HashMap<String, AssetRecord> = manifest
+        .into_iter()
+        .flat_map(|m| m.assets)
+        .map(|r| (format!("assets/{}", r.name), r))
+        .collect();
+
+    let bytes_total = (0..zip.len())
+        .filter_map(|i| zip.by_index_raw(i).ok().map(|f| f.size()))
+        .sum();
+    let mut tracker = ProgressTracker::new(channel, token, bytes_total);
+    let mut assets = HashSet::<String>::new();
+    let mut has_source = false;
+
+    for i in 0..zip.len() {
+        token.check()?;
+        let name = zip.name_for_index(i).unwrap_or_default().to_string();
+        let mut file = match zip.by_index(i) {
+            Ok(file) => file,
+            Err(e) => {
+                expected.remove(&name);
+                report.corrupted(&name, Corruption::Unreadable { msg: e.to_string() });
+                continue;
+            }
+        };
+        if file.is_dir() {
+            continue;
+        }
+
+        let record = expected.remove(&name);
+        if let Some(asset) = name.strip_prefix("assets/") {
+            assets.insert(asset.to_string());
+        }
+        if name == SOURCE_NAME {
+            has_source = true;
+        } else if record.is_none() && name != MANIFEST_NAME
+            && (report.has_manifest || !name.starts_with("assets/"))
+        {
+            report.extra.push(name.clone());
+        }
+
+        let mut hasher = Sha256::new();
+        match tracker.copy(&name, &mut file, &mut hasher) {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => report.corrupted(&name, Corruption::Unreadable { msg: e.to_string() }),
+            Ok(size) => if let Some(record) = record {
+                report.check_record(&record, size, &format!("{:x}", hasher.finalize()));
+            },
+        }
+    }
+
+    report.missing = expected.into_keys().collect();
+    report.missing.sort();
+
+    if !has_source {
+        report.missing.insert(0, SOURCE_NAME.to_string());
+    } else if !report.corrupted.iter().any(|c| c.entry == SOURCE_NAME) {
+        match read_source(zip, &ExtractLimits::default(), 0) {
+            Ok(source) => {
+                let unresolved: BTreeSet<String> = syntax::arguments(&source)
+                    .into_iter()
+                    .filter_map(|arg| arg.value?.strip_prefix("asset:").map(str::to_string))
+                    .filter(|name| !assets.contains(name))
+                    .collect();
+                report.unresolved = unresolved.into_iter().collect();
+            }
+            Err(e) => report.corrupted(SOURCE_NAME, Corruption::Unreadable { msg: e.to_string() }),
+        }
+    }
+    Ok(report)
+}
+
+/// Checks a bundle for missing, extra and corrupted entries and for `asset:`
+/// references that do not resolve.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn verify_archive(
+    channel: Channel<Progress>, path: String, id: String,
+    operations: State<'_, Arc<Operations>>,
+) -> Result<VerifyReport, ArchiveError> {
+    let operation = operations.register(id);
+    tauri::async_runtime::spawn_blocking(move || {
+        let file = File::open(&path)?;
+        let mut zip = ZipArchive::new(BufReader::new(file))
+            .map_err(|e| anyhow!("{path} is not a readable bundle: {e}"))?;
+        verify(&mut zip, &channel, operation.token())
+    })
+    .await
+    .map_err(|e| ArchiveError::Failed { msg: e.to_string() })?
+    .map_err(ArchiveError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::super::test_bundles::{bundle, record};
+    use super::*;
+
+    fn run(data: Vec<u8>) -> VerifyReport {
+        let mut zip = ZipArchive::new(Cursor::new(data)).unwrap();
+        verify(&mut zip, &Channel::new(|_| Ok(())), &CancellationToken::default()).unwrap()
+    }
+
+    const SOURCE: &[u8] = b"[.image asset:a.png;]\n[.image asset:b.png;]";
+
+    #[test]
+    fn intact_bundle() {
+        let report = run(bundle(
+            Some(vec![record("a.png", b"aaa"), record("b.png", b"bbb")]),
+            &[(SOURCE_NAME, SOURCE), ("assets/a.png", b"aaa"), ("assets/b.png", b"bbb")]));
+        assert!(report.has_manifest);
+        assert!(report.missing.is_empty());
+        assert!(report.extra.is_empty());
+        assert!(report.corrupted.is_empty());
+        assert!(report.unresolved.is_empty());
+    }
+
+    #[test]
+    fn missing_extra_and_unresolved() {
+        let report = run(bundle(
+            Some(vec![record("a.png", b"aaa"), record("c.png", b"ccc")]),
+            &[(SOURCE_NAME, SOURCE), ("assets/a.png", b"aaa"), ("assets/d.png", b"ddd")]));
+        assert_eq!(report.missing, ["assets/c.png"]);
+        assert_eq!(report.extra, ["assets/d.png"]);
+        assert_eq!(report.unresolved, ["b.png"]);
+        assert!(report.corrupted.is_empty());
+    }
+
+    #[test]
+    fn tampered_assets() {
+        let report = run(bundle(
+            Some(vec![record("a.png", b"aaa"), record("b.png", b"bbb")]),
+            &[(SOURCE_NAME, SOURCE), ("assets/a.png", b"aab"), ("assets/b.png", b"bbbb")]));
+        assert_eq!(report.corrupted.len(), 2);
+        assert_eq!(report.corrupted[0].entry, "assets/a.png");
+        assert_eq!(report.corrupted[0].corruption, Corruption::HashMismatch);
+        assert_eq!(report.corrupted[1].corruption,
+            Corruption::SizeMismatch { expected: 3, actual: 4 });
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut data = bundle(
+            Some(vec![record("a.png", b"aaa")]),
+            &[(SOURCE_NAME, b"[.image asset:a.png;]"), ("assets/a.png", b"aaa")]);
+        let at = data.windows(3).position(|w| w == b"aaa").unwrap();
+        data[at] = b'x';
+        let report = run(data);
+        assert_eq!(report.corrupted.len(), 1);
+        assert_eq!(report.corrupted[0].entry, "assets/a.png");
+        assert!(matches!(report.corrupted[0].corruption, Corruption::Unreadable { .. }));
+    }
+
+    #[test]
+    fn legacy_bundle() {
+        let report = run(bundle(None,
+            &[("assets/a.png", b"aaa"), ("assets/b.png", b"bbb"), ("notes.txt", b"")]));
+        assert!(!report.has_manifest);
+        assert_eq!(report.missing, [SOURCE_NAME]);
+        assert_eq!(report.extra, ["notes.txt"]);
+        assert!(report.corrupted.is_empty());
+    }
+}
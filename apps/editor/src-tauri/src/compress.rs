@@ -1,185 +1,1021 @@
-use std::{fs, io::Cursor};
+use std::{borrow::Cow, io::Cursor};
 
 use fast_image_resize::{images::Image, IntoImageView, Resizer};
-use image::{codecs::jpeg::JpegEncoder, codecs::png::PngEncoder, DynamicImage, ExtendedColorType, ImageEncoder, ImageReader};
+use image::{codecs::avif::AvifEncoder, codecs::jpeg::JpegEncoder, codecs::png::PngEncoder, codecs::webp::WebPEncoder, error::ImageError, metadata::Orientation, DynamicImage, ExtendedColorType, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, ImageResult, RgbImage, RgbaImage};
 use num_traits::ToPrimitive;
-use tauri::ipc::Response;
+use serde::{Deserialize, Serialize};
+use tauri::ipc::{Request, Response};
 
-#[derive(Clone, Copy)]
+mod animation;
+mod batch;
+mod cache;
+mod color;
+mod crop;
+mod metadata;
+mod palette;
+mod probe;
+mod source;
+#[cfg(test)]
+pub mod test_images;
+mod thumbnail;
+
+pub use batch::compress_images;
+pub use crop::crop_image;
+pub use probe::probe_image;
+pub use source::ImageSource;
+pub use thumbnail::{THUMBNAIL_CACHE_CAPACITY, Thumbnails, serve_thumbnail};
+
+use animation::Animation;
+use cache::ImageCache;
+use metadata::Copyright;
+
+/// Quality of lossy encodings when the size allows, on the JPEG scale
+const MAX_QUALITY: u8 = 80;
+pub const DEFAULT_MIN_QUALITY: u8 = 50;
+pub const DEFAULT_MAX_ITERATIONS: usize = 8;
+/// The quality search stops when it is narrowed down to this
+const QUALITY_STEP: u8 = 3;
+/// Animations are scaled down at most this much before frames are dropped
+const MIN_ANIMATION_SCALE: f64 = 0.5;
+/// Keep at least every this many frames
+const MAX_FRAME_STEP: usize = 3;
+/// Share of partly transparent pixels up to which an image may be flattened
+const MAX_PARTIAL_ALPHA: f64 = 0.05;
+/// AVIF looks about as good as JPEG at a lower quality setting
+const AVIF_QUALITY_OFFSET: u8 = 10;
+/// 0-10, where 10 is the fastest. Below 8, encoding a large image takes
+/// seconds for a few percent of size.
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 enum OutputFormat {
     Jpeg,
     Png,
+    /// PNG with at most 256 colors
+    PngPalette,
+    WebpLossy,
+    WebpLossless,
+    Avif,
+    /// Only for animations
+    Gif,
 }
 
 impl OutputFormat {
+    /// GIF and palette PNG count as lossy, since their palettes get smaller
+    /// with the quality.
+    fn is_lossy(self) -> bool {
+        matches!(self,
+            OutputFormat::Jpeg | OutputFormat::PngPalette | OutputFormat::WebpLossy
+                | OutputFormat::Avif | OutputFormat::Gif)
+    }
+
     fn mime(self) -> &'static str {
         match self {
             OutputFormat::Jpeg => "image/jpeg",
-            OutputFormat::Png => "image/png",
+            OutputFormat::Png | OutputFormat::PngPalette => "image/png",
+            OutputFormat::WebpLossy | OutputFormat::WebpLossless => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+
+    /// The format to use for a requested type. WebP is lossy, since it
+    /// keeps alpha either way.
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "image/jpeg" => Some(OutputFormat::Jpeg),
+            "image/png" => Some(OutputFormat::Png),
+            "image/webp" => Some(OutputFormat::WebpLossy),
+            "image/avif" => Some(OutputFormat::Avif),
+            _ => None,
         }
     }
 
     fn ext(self) -> &'static str {
         match self {
             OutputFormat::Jpeg => "jpg",
-            OutputFormat::Png => "png",
+            OutputFormat::Png | OutputFormat::PngPalette => "png",
+            OutputFormat::WebpLossy | OutputFormat::WebpLossless => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Gif => "gif",
         }
     }
 }
 
-fn prepare_image(img: &DynamicImage) -> (OutputFormat, DynamicImage) {
+/// Formats worth trying for an image. JPEG or PNG is always among them, since
+/// every caller can display those; the others need to be in `supported_types`.
+fn candidates(alpha: bool, supported_types: &[String]) -> Vec<OutputFormat> {
+    let supports = |mime: &str| supported_types.iter().any(|x| x == mime);
+    let mut formats = vec![if alpha { OutputFormat::Png } else { OutputFormat::Jpeg }];
+    if supports("image/webp") {
+        formats.push(OutputFormat::WebpLossy);
+        // lossless only pays off for graphics, which are the ones with alpha
+        if alpha {
+            formats.push(OutputFormat::WebpLossless);
+        }
+    }
+    if supports("image/avif") {
+        formats.push(OutputFormat::Avif);
+    }
+    formats
+}
+
+/// Whether a format can carry an ICC profile, for images whose profile
+/// could not be converted to sRGB
+fn keeps_profile(format: OutputFormat) -> bool {
+    // image does not write profiles into AVIF, and webp encodes bare frames
+    matches!(format, OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::WebpLossless)
+}
+
+/// Whether the transparency of an image is simple enough to flatten: few
+/// pixels are partly transparent, as along the anti-aliased edges of a logo,
+/// unlike soft shadows or fades.
+fn simple_transparency(img: &DynamicImage) -> bool {
+    let rgba8 = img.as_rgba8().map_or_else(|| Cow::Owned(img.to_rgba8()), Cow::Borrowed);
+    let pixels = rgba8.as_raw().len() / 4;
+    let partial = rgba8.as_raw().chunks_exact(4).filter(|x| x[3] != 0 && x[3] != 255).count();
+    partial.to_f64().unwrap() <= pixels.to_f64().unwrap() * MAX_PARTIAL_ALPHA
+}
+
+/// Composites RGBA8 pixels onto an opaque color.
+fn flatten(rgba8: &RgbaImage, background: [u8; 3]) -> RgbImage {
+    RgbImage::from_fn(rgba8.width(), rgba8.height(), |x, y| {
+        let [r, g, b, a] = rgba8.get_pixel(x, y).0;
+        let a = u32::from(a);
+        let mut px = [r, g, b];
+        for (v, bg) in px.iter_mut().zip(background) {
+            *v = ((u32::from(*v) * a + u32::from(bg) * (255 - a) + 127) / 255).to_u8().unwrap();
+        }
+        image::Rgb(px)
+    })
+}
+
+/// Converts to RGB8, or to RGBA8 if any pixel is transparent. With a
+/// `background`, a transparent image is flattened onto it instead. Returns
+/// whether the alpha channel was kept.
+fn prepare_image(img: &DynamicImage, background: Option<[u8; 3]>) -> (bool, DynamicImage) {
     if !img.color().has_alpha() {
-        return (OutputFormat::Jpeg, DynamicImage::ImageRgb8(img.to_rgb8()));
+        return (false, DynamicImage::ImageRgb8(img.to_rgb8()));
     }
     let rgba8 = img.to_rgba8();
     let opaque = rgba8.as_raw().chunks_exact(4).all(|x| x[3] == 255);
     if opaque {
-        (OutputFormat::Jpeg, DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba8).into_rgb8()))
+        (false, DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba8).into_rgb8()))
+    } else if let Some(background) = background {
+        (false, DynamicImage::ImageRgb8(flatten(&rgba8, background)))
     } else {
-        (OutputFormat::Png, DynamicImage::ImageRgba8(rgba8))
+        (true, DynamicImage::ImageRgba8(rgba8))
     }
 }
 
-fn try_compress_size(img: &DynamicImage, scaling: f64, format: OutputFormat) -> Result<Vec<u8>, String> {
-    let width = (f64::from(img.width()) * scaling).to_u32().unwrap();
-    let height = (f64::from(img.height()) * scaling).to_u32().unwrap();
+/// Width and height of an image scaled by `scaling`, of at least a pixel.
+fn scaled_size(width: u32, height: u32, scaling: f64) -> (u32, u32) {
+    (
+        (f64::from(width) * scaling).to_u32().unwrap().max(1),
+        (f64::from(height) * scaling).to_u32().unwrap().max(1),
+    )
+}
 
+/// Returns the pixels of the scaled image, with its width and height.
+fn scaled(img: &DynamicImage, scaling: f64) -> Result<(Cow<'_, [u8]>, u32, u32), String> {
+    let (width, height) = scaled_size(img.width(), img.height(), scaling);
     if width == img.width() {
-        log::info!("try_compress_size: encoding");
-        encode_dynamic(img, format)
-    } else {
-        log::info!("try_compress_size: resizing {width} x {height}");
-        let mut dst = Image::new(width, height, img.pixel_type().unwrap());
-        Resizer::new()
-            .resize(img, &mut dst, None)
-            .map_err(|e| format!("resize: {e}"))?;
-        log::info!("try_compress_size: encoding");
-        encode_raw(dst.buffer(), width, height, format)
+        return Ok((Cow::Borrowed(img.as_bytes()), width, height));
     }
+    log::info!("try_compress_size: resizing {width} x {height}");
+    let mut dst = Image::new(width, height, img.pixel_type().unwrap());
+    Resizer::new()
+        .resize(img, &mut dst, None)
+        .map_err(|e| format!("resize: {e}"))?;
+    Ok((Cow::Owned(dst.into_vec()), width, height))
 }
 
-fn encode_dynamic(img: &DynamicImage, format: OutputFormat) -> Result<Vec<u8>, String> {
-    let mut out = Vec::new();
-    match format {
-        OutputFormat::Jpeg => {
-            let encoder = JpegEncoder::new_with_quality(&mut out, 80);
-            img.write_with_encoder(encoder)
-                .map_err(|e| format!("encode: {e}"))?;
-        }
-        OutputFormat::Png => {
-            let encoder = PngEncoder::new(&mut out);
-            img.write_with_encoder(encoder)
-                .map_err(|e| format!("encode: {e}"))?;
+/// Encodes the scaled image in each of `formats` and returns the smallest.
+/// `icc` is stored with it if given.
+fn try_compress_size(
+    img: &DynamicImage, scaling: f64, quality: u8, formats: &[OutputFormat], icc: Option<&[u8]>
+) -> Result<(OutputFormat, Vec<u8>), String> {
+    let color = ExtendedColorType::from(img.color());
+    let (buf, width, height) = scaled(img, scaling)?;
+
+    let mut best: Option<(OutputFormat, Vec<u8>)> = None;
+    for &format in formats {
+        log::info!("try_compress_size: encoding {format:?} at quality {quality}");
+        let data = encode(&buf, width, height, color, format, quality, icc)?;
+        if best.as_ref().is_none_or(|(_, b)| data.len() < b.len()) {
+            best = Some((format, data));
         }
     }
-    Ok(out)
+    best.ok_or("no output format".to_owned())
+}
+
+fn write_image(
+    mut encoder: impl ImageEncoder, buf: &[u8], width: u32, height: u32,
+    color: ExtendedColorType, icc: Option<&[u8]>,
+) -> ImageResult<()> {
+    if let Some(icc) = icc {
+        encoder.set_icc_profile(icc.to_vec()).map_err(ImageError::Unsupported)?;
+    }
+    encoder.write_image(buf, width, height, color)
 }
 
-fn encode_raw(buf: &[u8], width: u32, height: u32, format: OutputFormat) -> Result<Vec<u8>, String> {
+fn encode(
+    buf: &[u8], width: u32, height: u32, color: ExtendedColorType, format: OutputFormat,
+    quality: u8, icc: Option<&[u8]>,
+) -> Result<Vec<u8>, String> {
     let mut out = Vec::new();
-    let color = match format {
-        OutputFormat::Jpeg => ExtendedColorType::Rgb8,
-        OutputFormat::Png => ExtendedColorType::Rgba8,
+    let result = match format {
+        OutputFormat::Jpeg => write_image(
+            JpegEncoder::new_with_quality(&mut out, quality), buf, width, height, color, icc),
+        OutputFormat::Png =>
+            write_image(PngEncoder::new(&mut out), buf, width, height, color, icc),
+        OutputFormat::WebpLossless =>
+            write_image(WebPEncoder::new_lossless(&mut out), buf, width, height, color, icc),
+        OutputFormat::Avif => write_image(
+            AvifEncoder::new_with_speed_quality(
+                &mut out, AVIF_SPEED, quality.saturating_sub(AVIF_QUALITY_OFFSET)),
+            buf, width, height, color, icc),
+        OutputFormat::PngPalette =>
+            return palette::encode_png(buf, width, height, color, palette::palette_size(quality)),
+        OutputFormat::Gif => return Err("GIF is only used for animations".to_owned()),
+        OutputFormat::WebpLossy => {
+            // the image crate only encodes lossless WebP
+            let encoder = if color == ExtendedColorType::Rgba8 {
+                webp::Encoder::from_rgba(buf, width, height)
+            } else {
+                webp::Encoder::from_rgb(buf, width, height)
+            };
+            let data = encoder.encode_simple(false, f32::from(quality))
+                .map_err(|e| format!("encode: {e:?}"))?;
+            return Ok(data.to_vec());
+        }
     };
-    match format {
-        OutputFormat::Jpeg => {
-            let encoder = JpegEncoder::new_with_quality(&mut out, 80);
-            encoder.write_image(buf, width, height, color)
-                .map_err(|e| format!("encode: {e}"))?;
+    result.map_err(|e| format!("encode: {e}"))?;
+    Ok(out)
+}
+
+/// Layout of packed images; see `CompressedImage::pack`. The frontend
+/// checks it, so change it whenever the layout or the header does.
+const PACK_VERSION: u32 = 1;
+
+/// What was done to an image.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    /// After the EXIF orientation is applied
+    pub original_width: u32,
+    pub original_height: u32,
+    /// Of the lossy encoding, if it is one and the image was encoded
+    pub quality: Option<u8>,
+    /// Width of the result over that of the original, or of the cropped part
+    pub scale: f64,
+    /// The original was kept, with its metadata removed
+    pub passthrough: bool,
+}
+
+impl ImageInfo {
+    fn unchanged(width: u32, height: u32) -> Self {
+        ImageInfo {
+            width, height,
+            original_width: width,
+            original_height: height,
+            quality: None,
+            scale: 1.0,
+            passthrough: true,
         }
-        OutputFormat::Png => {
-            let encoder = PngEncoder::new(&mut out);
-            encoder.write_image(buf, width, height, color)
-                .map_err(|e| format!("encode: {e}"))?;
+    }
+
+    /// For `best`, encoded from an image of the given size.
+    fn encoded(best: &Candidate, original_width: u32, original_height: u32) -> Self {
+        let (width, height) = scaled_size(original_width, original_height, best.scale);
+        ImageInfo {
+            width, height, original_width, original_height,
+            quality: best.format.is_lossy().then_some(best.quality),
+            scale: best.scale,
+            passthrough: false,
         }
     }
-    Ok(out)
 }
 
-fn pack_image_result(mime: &str, ext: &str, data: Vec<u8>) -> Vec<u8> {
-    let mut buf: Vec<u8> = vec![];
-    buf.extend((mime.len().to_u32().unwrap()).to_le_bytes());
-    buf.extend(mime.as_bytes());
-    buf.extend((ext.len().to_u32().unwrap()).to_le_bytes());
-    buf.extend(ext.as_bytes());
-    buf.extend(data);
-    buf
+/// The JSON header of a packed image.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PackHeader {
+    mime: String,
+    ext: String,
+    #[serde(flatten)]
+    info: ImageInfo,
 }
 
-#[tauri::command]
-#[allow(clippy::needless_pass_by_value)]
-pub async fn compress_image(
-    path: String,
+/// An encoded image, ready to be stored or sent to the frontend.
+pub struct CompressedImage {
+    pub mime: &'static str,
+    pub ext: &'static str,
+    pub data: Vec<u8>,
+    pub info: ImageInfo,
+}
+
+impl CompressedImage {
+    fn new(format: OutputFormat, data: Vec<u8>, info: ImageInfo) -> Self {
+        CompressedImage { mime: format.mime(), ext: format.ext(), data, info }
+    }
+
+    /// The original image, with its metadata removed.
+    fn unchanged(format: ImageFormat, data: Vec<u8>, width: u32, height: u32) -> Self {
+        let ext = format.extensions_str().first().map_or("", |v| v);
+        CompressedImage {
+            mime: format.to_mime_type(), ext, data, info: ImageInfo::unchanged(width, height)
+        }
+    }
+
+    /// Packs the image for the frontend: `PACK_VERSION` and the length of
+    /// the header as little-endian u32, the header as JSON, then the data.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn pack(self) -> Vec<u8> {
+        let header = serde_json::to_vec(&PackHeader {
+            mime: self.mime.to_owned(),
+            ext: self.ext.to_owned(),
+            info: self.info,
+        }).unwrap();
+        let mut buf: Vec<u8> = vec![];
+        buf.extend(PACK_VERSION.to_le_bytes());
+        buf.extend((header.len().to_u32().unwrap()).to_le_bytes());
+        buf.extend(header);
+        buf.extend(self.data);
+        buf
+    }
+}
+
+fn default_min_quality() -> u8 {
+    DEFAULT_MIN_QUALITY
+}
+
+fn default_max_iterations() -> usize {
+    DEFAULT_MAX_ITERATIONS
+}
+
+/// What `compress` should produce.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressOptions {
+    /// Budget in bytes
+    pub max_size: usize,
+    /// Wider images are scaled down to this width
+    pub max_width: Option<usize>,
+    /// Types the result may have; see `candidates`
+    pub supported_types: Vec<String>,
+    /// Keep the artist and copyright notice from the EXIF data. All other
+    /// metadata is always removed.
+    #[serde(default)]
+    pub keep_copyright: bool,
+    /// Lowest quality that lossy encodings may go down to before the image
+    /// is scaled down further
+    #[serde(default = "default_min_quality")]
+    pub min_quality: u8,
+    /// Number of encodings to try at most; for animations, at each number
+    /// of frames
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: usize,
+    /// Compress only the first frame of animations
+    #[serde(default)]
+    pub first_frame: bool,
+    /// Flatten images whose transparency is simple onto this color, for
+    /// pages whose background is known, so that they can be JPEG
+    #[serde(default)]
+    pub background: Option<[u8; 3]>,
+    /// Also try PNG with a palette, which suits flat graphics such as
+    /// screenshots and diagrams
+    #[serde(default)]
+    pub palette: bool,
+}
+
+struct Candidate {
+    format: OutputFormat,
+    data: Vec<u8>,
+    scale: f64,
+    quality: u8,
+}
+
+type Encode<'a> = dyn Fn(f64, u8) -> Result<(OutputFormat, Vec<u8>), String> + 'a;
+
+/// Looks for the largest scale, then the highest quality, that fits the budget.
+struct Search<'a> {
+    /// Encodes at a scale and quality
+    encode: &'a Encode<'a>,
+    /// Smallest scale to try
+    min_scale: f64,
     max_size: usize,
-    max_width: Option<usize>,
-    supported_types: Vec<String>
-) -> Result<Response, String> {
-    log::info!("compress_image start");
-    let result =
-    tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
-        let original =
-            fs::read(path.clone()).map_err(|e| format!("fs::read: {e}"))?;
-        let reader =
-            ImageReader::new(Cursor::new(original.as_slice()))
-            .with_guessed_format()
-            .map_err(|e| format!("with_guessed_format: {e}"))?;
-        let format = reader
-            .format()
-            .ok_or("with_guessed_format: cannot guess format".to_owned())?;
-        let img = reader.decode().map_err(|e| format!("decode: {e}"))?;
-
-        log::info!("compress_image decoded image");
-
-        let (output_format, img) = prepare_image(&img);
-
-        let mut r: f64 = 1.0;
-        if let Some(mw) = max_width {
-            r = r.min(mw.to_f64().unwrap() / img.width().to_f64().unwrap());
-        }
-
-        if supported_types.iter().any(|x| *x == format.to_mime_type()) {
-            if original.len() < max_size {
-                let ext = format.extensions_str().first().map_or("", |v| v);
-                return Ok(pack_image_result(format.to_mime_type(), ext, original));
-            }
+    iterations_left: usize,
+    /// Best encoding that fits
+    best: Option<Candidate>,
+    /// Size of the last encoding
+    last_size: usize,
+    /// Smallest size reached, for the error message
+    smallest: usize,
+}
 
-            let result = try_compress_size(&img, 1.0, output_format)?;
-            if result.len() < max_size {
-                return Ok(pack_image_result(output_format.mime(), output_format.ext(), result));
-            }
+impl<'a> Search<'a> {
+    fn new(encode: &'a Encode<'a>, min_scale: f64, max_size: usize, max_iterations: usize) -> Self {
+        Search {
+            encode,
+            min_scale,
+            max_size,
+            iterations_left: max_iterations.max(1),
+            best: None,
+            last_size: 0,
+            smallest: usize::MAX,
+        }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.iterations_left == 0
+    }
+
+    /// Tries one encoding and tells whether it fits.
+    fn fits(&mut self, scale: f64, quality: u8) -> Result<bool, String> {
+        self.iterations_left = self.iterations_left.saturating_sub(1);
+        let (format, data) = (self.encode)(scale, quality)?;
+        self.last_size = data.len();
+        self.smallest = self.smallest.min(data.len());
+        if data.len() >= self.max_size {
+            return Ok(false);
+        }
+        let better = self.best.as_ref().is_none_or(|b| (scale, quality) > (b.scale, b.quality));
+        if better {
+            self.best = Some(Candidate { format, data, scale, quality });
         }
+        Ok(true)
+    }
+
+    fn run(&mut self, r: f64, min_quality: u8) -> Result<(), String> {
+        if self.fits(r, MAX_QUALITY)? {
+            return Ok(());
+        }
+        let passable_size = (self.max_size.to_f64().unwrap() * 0.9).to_usize().unwrap();
 
-        let mut l = 0.1;
-        let mut last_ok: Option<Vec<u8>> = None;
-        let passable_size = (max_size.to_f64().unwrap() * 0.9).to_usize().unwrap();
+        // the scale, at the lowest quality
+        let mut lo: Option<(f64, usize)> = None;
+        let mut hi = r;
+        let mut hi_size = self.last_size;
+        if min_quality < MAX_QUALITY {
+            if self.exhausted() {
+                return Ok(());
+            }
+            if self.fits(r, min_quality)? {
+                lo = Some((r, self.last_size));
+            }
+            hi_size = self.last_size;
+        }
+        while !self.exhausted() {
+            let guess = match lo {
+                Some((l, size)) if size > passable_size || hi - l < 0.01 => break,
+                Some((l, _)) => (l + hi) * 0.5,
+                // the size is roughly proportional to the area
+                None => hi * (passable_size.to_f64().unwrap() / hi_size.to_f64().unwrap()).sqrt(),
+            };
+            let guess = guess.max(self.min_scale);
+            if guess >= hi {
+                break;
+            }
+            if self.fits(guess, min_quality)? {
+                lo = Some((guess, self.last_size));
+            } else {
+                hi = guess;
+                hi_size = self.last_size;
+            }
+        }
+        let Some((scale, _)) = lo else { return Ok(()) };
 
-        for _ in 0..3 {
-            let guess = (l + r) * 0.5;
-            let result = try_compress_size(&img, guess, output_format)?;
-            let size = result.len();
-            if size < max_size {
-                l = guess;
-                last_ok = Some(result);
-                if size > passable_size { break; }
+        // then the quality, with what is left of the budget
+        let (mut ql, mut qh) = (min_quality, MAX_QUALITY);
+        if scale < r && !self.exhausted() && self.fits(scale, qh)? {
+            return Ok(());
+        }
+        while qh - ql > QUALITY_STEP && !self.exhausted() {
+            let mid = ql + (qh - ql) / 2;
+            if self.fits(scale, mid)? {
+                ql = mid;
             } else {
-                r = guess;
+                qh = mid;
             }
         }
-        let result = last_ok
-            .ok_or("Unable to compress within size limit".to_owned())?;
-        Ok(pack_image_result(output_format.mime(), output_format.ext(), result))
-    }).await;
+        Ok(())
+    }
+}
+
+/// A still image, or the first frame of an animation, in sRGB and upright.
+struct Decoded {
+    img: DynamicImage,
+    exif: Option<Vec<u8>>,
+    orientation: Orientation,
+    /// Color profile that could not be converted, to store with the image
+    icc: Option<Vec<u8>>,
+    cmyk: bool,
+}
+
+fn guess_format(data: &[u8]) -> Result<ImageFormat, String> {
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| format!("with_guessed_format: {e}"))?
+        .format()
+        .ok_or("with_guessed_format: cannot guess format".to_owned())
+}
+
+fn decode(original: &[u8], format: ImageFormat) -> Result<Decoded, String> {
+    let mut decoder = ImageReader::with_format(Cursor::new(original), format)
+        .into_decoder()
+        .map_err(|e| format!("decode: {e}"))?;
+    let exif = decoder.exif_metadata().unwrap_or_else(|e| {
+        log::warn!("compress_image: unreadable EXIF: {e}");
+        None
+    });
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let icc = decoder.icc_profile().unwrap_or_else(|e| {
+        log::warn!("compress_image: unreadable color profile: {e}");
+        None
+    });
+    let cmyk = match format {
+        ImageFormat::Jpeg => color::decode_cmyk_jpeg(original, icc.as_deref())?,
+        _ => None,
+    };
+    let is_cmyk = cmyk.is_some();
+    let (mut img, icc) = if let Some(img) = cmyk {
+        (img, None)
+    } else {
+        let img = DynamicImage::from_decoder(decoder).map_err(|e| format!("decode: {e}"))?;
+        color::to_srgb(&img, icc)
+    };
+    img.apply_orientation(orientation);
+    log::info!("compress_image decoded image");
+    Ok(Decoded { img, exif, orientation, icc, cmyk: is_cmyk })
+}
+
+/// Fits an encoded image within `max_size` bytes and `max_width` pixels,
+/// returning it unchanged if it already fits and is of a supported type.
+/// The EXIF orientation is applied to the pixels, and the metadata that may
+/// identify the camera or the place is removed, from the original as well.
+/// Pixels are converted to sRGB when the image has another color profile,
+/// or CMYK colors.
+pub fn compress(original: &[u8], options: &CompressOptions) -> Result<CompressedImage, String> {
+    let format = guess_format(original)?;
+    let mime = format.to_mime_type();
+    let animated = metadata::frame_count(mime, original).is_some_and(|n| n > 1);
+    if animated && !options.first_frame {
+        match animation::decode(format, original) {
+            Ok(Some(animation)) => return compress_animation(original, format, &animation, options),
+            Ok(None) => {}
+            Err(e) => log::warn!("compress: {e}; keeping the first frame only"),
+        }
+    }
+    let Decoded { img, exif, orientation, icc, cmyk } = decode(original, format)?;
+
+    let copyright = exif.as_deref()
+        .filter(|_| options.keep_copyright)
+        .and_then(Copyright::from_exif)
+        .map(|c| c.to_exif());
+    let exif_size = copyright.as_ref().map_or(0, Vec::len);
+    let max_size = options.max_size.saturating_sub(exif_size);
+    let (original_width, original_height) = (img.width(), img.height());
+    let finish = |best: Candidate| {
+        let info = ImageInfo::encoded(&best, original_width, original_height);
+        let data = match &copyright {
+            Some(exif) => metadata::rewrite(best.format.mime(), &best.data, Some(exif))
+                .ok_or("cannot store copyright".to_owned())?,
+            None => best.data,
+        };
+        Ok(CompressedImage::new(best.format, data, info))
+    };
+
+    // flattened, soft shadows and fades would only suit that background
+    let background = options.background.filter(|_| simple_transparency(&img));
+    let (alpha, img) = prepare_image(&img, background);
+    let mut formats = candidates(alpha, &options.supported_types);
+    if options.palette {
+        formats.push(OutputFormat::PngPalette);
+    }
+    if copyright.is_some() {
+        // AVIF keeps EXIF in a way that we cannot write
+        formats.retain(|&f| f != OutputFormat::Avif);
+    }
+    if icc.is_some() {
+        formats.retain(|&f| keeps_profile(f));
+    }
+
+    let mut r: f64 = 1.0;
+    if let Some(mw) = options.max_width {
+        r = r.min(mw.to_f64().unwrap() / img.width().to_f64().unwrap());
+    }
+
+    if options.supported_types.iter().any(|x| *x == mime) {
+        // a rotated image cannot be passed through once its EXIF is gone,
+        // nor an animation of which only the first frame is kept, nor CMYK,
+        // which not every reader shows correctly
+        let unchanged = (orientation == Orientation::NoTransforms && r >= 1.0
+            && !animated && !cmyk)
+            .then(|| metadata::rewrite(mime, original, copyright.as_deref()))
+            .flatten();
+        if let Some(data) = unchanged.filter(|d| d.len() < options.max_size) {
+            return Ok(CompressedImage::unchanged(format, data, original_width, original_height));
+        }
+    }
+
+    let min_quality = if formats.iter().any(|f| f.is_lossy()) {
+        options.min_quality.min(MAX_QUALITY)
+    } else {
+        MAX_QUALITY
+    };
+    let encode = |scale, quality| try_compress_size(&img, scale, quality, &formats, icc.as_deref());
+    // no dimension may go below a pixel
+    let min_scale = 1.0 / f64::from(img.width().min(img.height()));
+    let mut search = Search::new(&encode, min_scale, max_size, options.max_iterations);
+    search.run(r, min_quality)?;
+    let Some(best) = search.best else {
+        return Err(too_large(search.smallest + exif_size));
+    };
+    log::info!("compress_image: scale {:.3}, quality {}", best.scale, best.quality);
+    finish(best)
+}
+
+/// Like `compress`, for animated GIF and WebP. Frames are dropped, evenly,
+/// only when the animation does not fit at `MIN_ANIMATION_SCALE`.
+fn compress_animation(
+    original: &[u8], format: ImageFormat, animation: &Animation, options: &CompressOptions
+) -> Result<CompressedImage, String> {
+    let mut r: f64 = 1.0;
+    if let Some(mw) = options.max_width {
+        r = r.min(mw.to_f64().unwrap() / f64::from(animation.width()));
+    }
+
+    let supports = |mime: &str| options.supported_types.iter().any(|x| x == mime);
+    let mime = format.to_mime_type();
+    if supports(mime) && r >= 1.0
+        && let Some(data) = metadata::rewrite(mime, original, None)
+        && data.len() < options.max_size
+    {
+        return Ok(CompressedImage::unchanged(
+            format, data, animation.width(), animation.height()));
+    }
+
+    // every caller can display GIF
+    let mut formats = vec![OutputFormat::Gif];
+    if supports("image/webp") {
+        formats.push(OutputFormat::WebpLossy);
+    }
+    let mut smallest = usize::MAX;
+    for step in 1..=MAX_FRAME_STEP {
+        let encode = |scale, quality| animation.encode(scale, quality, step, &formats);
+        let mut search = Search::new(
+            &encode, r * MIN_ANIMATION_SCALE, options.max_size, options.max_iterations);
+        search.run(r, options.min_quality.min(MAX_QUALITY))?;
+        if let Some(best) = search.best {
+            log::info!("compress_image: scale {:.3}, quality {}, every {step} frames",
+                best.scale, best.quality);
+            let info = ImageInfo::encoded(&best, animation.width(), animation.height());
+            return Ok(CompressedImage::new(best.format, best.data, info));
+        }
+        smallest = smallest.min(search.smallest);
+        if animation.frame_count().div_ceil(step) <= 2 {
+            break;
+        }
+    }
+    Err(too_large(smallest))
+}
+
+fn too_large(smallest: usize) -> String {
+    format!("Unable to compress within size limit: the smallest result was {smallest} bytes")
+}
+
+/// Compresses the image from `source` and packs it for the frontend.
+fn compress_source(source: ImageSource, options: &CompressOptions) -> Result<Vec<u8>, String> {
+    let original = source.read()?;
+    compress(&original, options).map(CompressedImage::pack)
+}
+
+/// Runs `task` on a blocking thread and sends the packed image it returns.
+async fn respond(
+    name: &'static str, task: impl FnOnce() -> Result<Vec<u8>, String> + Send + 'static
+) -> Result<Response, String> {
+    log::info!("{name} start");
+    let result = tokio::task::spawn_blocking(task).await;
 
     match result {
         Ok(Ok(data)) => {
-            log::info!("compress_image done");
+            log::info!("{name} done");
             Ok(Response::new(data))
         }
         Ok(Err(e)) => {
-            Err(format!("compress_image task: {e}"))
+            Err(format!("{name} task: {e}"))
         }
         Err(e) => {
             Err(format!("tokio::task::spawn_blocking: {e}"))
         }
     }
 }
+
+/// Takes the image as the raw body of the request, with the options in a
+/// header, or `{ source, options }` where the source is a path or a URL;
+/// see `source::arguments`.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn compress_image(
+    request: Request<'_>,
+) -> Result<Response, String> {
+    let (source, options) = source::arguments(&request)?;
+    respond("compress_image", move || compress_source(source, &options)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::test_images::{self, gradient, noise};
+    use super::*;
+
+    /// A gradient with a transparent hole, encoded as PNG.
+    fn transparent_png(size: u32) -> Vec<u8> {
+        let img = RgbaImage::from_fn(size, size, |x, y| {
+            let alpha = if x < size / 2 && y < size / 2 { 0 } else { 255 };
+            Rgba([(x * 255 / size).to_u8().unwrap(), (y * 255 / size).to_u8().unwrap(), 128, alpha])
+        });
+        let mut out = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_with_encoder(PngEncoder::new(&mut out))
+            .unwrap();
+        out
+    }
+
+    fn types(types: &[&str]) -> Vec<String> {
+        types.iter().map(|t| (*t).to_string()).collect()
+    }
+
+    #[test]
+    fn candidates_follow_supported_types() {
+        use OutputFormat::*;
+        let basic = types(&["image/jpeg", "image/png"]);
+        let all = types(&["image/jpeg", "image/png", "image/webp", "image/avif"]);
+        assert_eq!(candidates(false, &basic), [Jpeg]);
+        assert_eq!(candidates(true, &basic), [Png]);
+        assert_eq!(candidates(false, &all), [Jpeg, WebpLossy, Avif]);
+        assert_eq!(candidates(true, &all), [Png, WebpLossy, WebpLossless, Avif]);
+    }
+
+    #[test]
+    fn picks_smallest_encoding() {
+        let img = image::load_from_memory(&transparent_png(64)).unwrap();
+        let (alpha, img) = prepare_image(&img, None);
+        assert!(alpha);
+        let (format, png) = try_compress_size(&img, 1.0, MAX_QUALITY, &[OutputFormat::Png], None).unwrap();
+        assert_eq!(format, OutputFormat::Png);
+
+        let all = types(&["image/webp", "image/avif"]);
+        let (format, data) = try_compress_size(&img, 1.0, MAX_QUALITY, &candidates(alpha, &all), None).unwrap();
+        assert_ne!(format, OutputFormat::Png);
+        assert!(data.len() < png.len());
+        let decoded = image::load_from_memory(&data).unwrap();
+        assert!(decoded.color().has_alpha());
+        assert_eq!(decoded.width(), 64);
+    }
+
+    fn jpeg_with_exif(width: u32, height: u32, exif: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_with_encoder(JpegEncoder::new(&mut out))
+            .unwrap();
+        metadata::rewrite("image/jpeg", &out, Some(exif)).unwrap()
+    }
+
+    fn read_exif(data: &[u8]) -> Option<Vec<u8>> {
+        let reader = ImageReader::new(Cursor::new(data)).with_guessed_format().unwrap();
+        reader.into_decoder().unwrap().exif_metadata().unwrap()
+    }
+
+    fn options(keep_copyright: bool) -> CompressOptions {
+        CompressOptions {
+            max_size: 1 << 20,
+            max_width: None,
+            supported_types: types(&["image/jpeg", "image/png"]),
+            keep_copyright,
+            min_quality: DEFAULT_MIN_QUALITY,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            first_frame: false,
+            background: None,
+            palette: false,
+        }
+    }
+
+    #[test]
+    fn applies_orientation() {
+        // IFD0 with Orientation = 6, i.e. rotate 90 degrees clockwise
+        let exif = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0";
+        let image = compress(&jpeg_with_exif(8, 4, exif), &options(false)).unwrap();
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (4, 8));
+        assert_eq!(read_exif(&image.data), None);
+    }
+
+    #[test]
+    fn strips_metadata_when_passing_through() {
+        let copyright = metadata::Copyright::from_exif(&{
+            let mut exif = b"II*\0\x08\0\0\0\x01\0\x98\x82\x02\0\x04\0\0\0abc\0".to_vec();
+            exif.extend(0u32.to_le_bytes());
+            exif
+        }).unwrap().to_exif();
+        let original = jpeg_with_exif(8, 4, &copyright);
+
+        let image = compress(&original, &options(false)).unwrap();
+        assert_eq!(image.mime, "image/jpeg");
+        assert_eq!(image.data.len() + copyright.len() + 10, original.len());
+        assert_eq!(read_exif(&image.data), None);
+
+        let image = compress(&original, &options(true)).unwrap();
+        assert_eq!(image.data, original);
+    }
+
+    /// Noise, which does not compress well.
+    fn noisy_png(size: u32) -> Vec<u8> {
+        let mut next = noise();
+        let img = image::RgbImage::from_fn(size, size, |_, _| {
+            image::Rgb((next() >> 16).to_le_bytes()[..3].try_into().unwrap())
+        });
+        let mut out = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_with_encoder(PngEncoder::new(&mut out))
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn lowers_quality_before_scale() {
+        let original = noisy_png(128);
+        let img = image::load_from_memory(&original).unwrap();
+        let (_, low) = try_compress_size(
+            &img, 1.0, DEFAULT_MIN_QUALITY, &[OutputFormat::Jpeg], None).unwrap();
+        let (_, high) = try_compress_size(&img, 1.0, MAX_QUALITY, &[OutputFormat::Jpeg], None).unwrap();
+
+        let max_size = low.len().midpoint(high.len());
+        let image = compress(&original, &CompressOptions { max_size, ..options(false) }).unwrap();
+        assert!(image.data.len() < max_size);
+        assert_eq!(image::load_from_memory(&image.data).unwrap().width(), 128);
+    }
+
+    #[test]
+    fn reports_smallest_size() {
+        let original = noisy_png(64);
+        let error = compress(&original, &CompressOptions {
+            max_size: 10, max_iterations: 3, ..options(false)
+        }).err().unwrap();
+        assert!(error.contains("the smallest result was"), "{error}");
+    }
+
+    #[test]
+    fn converts_cmyk() {
+        let cyan: Vec<u8> = [255, 0, 0, 0].repeat(16 * 16);
+        let mut original = Vec::new();
+        jpeg_encoder::Encoder::new(&mut original, 90)
+            .encode(&cyan, 16, 16, jpeg_encoder::ColorType::Cmyk)
+            .unwrap();
+        let image = compress(&original, &options(false)).unwrap();
+        assert_ne!(image.data, original);
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        let px = decoded.to_rgb8().get_pixel(8, 8).0;
+        assert!(px[0] < 16 && px[1] > 240 && px[2] > 240, "{px:?}");
+    }
+
+    /// Splits a packed image into its header and data.
+    fn unpack(packed: &[u8]) -> (PackHeader, &[u8]) {
+        let (version, rest) = packed.split_at(4);
+        assert_eq!(version, PACK_VERSION.to_le_bytes());
+        let (len, rest) = rest.split_at(4);
+        let (header, data) = rest.split_at(u32::from_le_bytes(len.try_into().unwrap()) as usize);
+        (serde_json::from_slice(header).unwrap(), data)
+    }
+
+    #[test]
+    fn packs_info() {
+        let info = ImageInfo {
+            width: 50, height: 25, original_width: 200, original_height: 100,
+            quality: Some(70), scale: 0.25, passthrough: false,
+        };
+        let packed = CompressedImage::new(OutputFormat::WebpLossy, b"data".to_vec(), info.clone()).pack();
+        let (header, data) = unpack(&packed);
+        assert_eq!(header, PackHeader { mime: "image/webp".to_owned(), ext: "webp".to_owned(), info });
+        assert_eq!(data, b"data");
+
+        let original = noisy_png(64);
+        let max_size = original.len() / 4;
+        let image = compress(&original, &CompressOptions { max_size, ..options(false) }).unwrap();
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        let (header, _) = unpack(&image.pack());
+        assert_eq!((header.info.width, header.info.height), (decoded.width(), decoded.height()));
+        assert_eq!((header.info.original_width, header.info.original_height), (64, 64));
+        assert_eq!(header.mime, "image/jpeg");
+        assert!(header.info.quality.is_some());
+        assert!(!header.info.passthrough);
+
+        let original = jpeg_with_exif(8, 4, b"II*\0\x08\0\0\0\0\0\0\0\0\0");
+        let (header, _) = unpack(&compress(&original, &options(false)).unwrap().pack());
+        assert_eq!(header.info, ImageInfo::unchanged(8, 4));
+    }
+
+    fn png(img: RgbaImage) -> Vec<u8> {
+        let mut out = Vec::new();
+        DynamicImage::ImageRgba8(img).write_with_encoder(PngEncoder::new(&mut out)).unwrap();
+        out
+    }
+
+    #[test]
+    fn flattens_simple_transparency() {
+        // a noisy square on a transparent background, with a soft edge
+        let mut next = noise();
+        let logo = png(RgbaImage::from_fn(64, 64, |x, y| {
+            let [r, g, b, _] = (next() >> 8).to_le_bytes();
+            match x.abs_diff(32).max(y.abs_diff(32)) {
+                ..16 => Rgba([r, g, b, 255]),
+                16 => Rgba([r, g, b, 128]),
+                _ => Rgba([0, 0, 0, 0]),
+            }
+        }));
+        let max_size = logo.len() / 2;
+        let white = CompressOptions { max_size, background: Some([255; 3]), ..options(false) };
+        let image = compress(&logo, &white).unwrap();
+        assert_eq!(image.mime, "image/jpeg");
+        let decoded = image::load_from_memory(&image.data).unwrap().to_rgb8();
+        assert!(decoded.get_pixel(2, 2).0.iter().all(|&v| v > 240));
+
+        // a fade is left transparent
+        let fade = RgbaImage::from_fn(64, 64, |x, _| Rgba([0, 0, 255, (x * 4).to_u8().unwrap()]));
+        assert!(!simple_transparency(&DynamicImage::ImageRgba8(fade)));
+    }
+
+    #[test]
+    fn quantizes_flat_graphics() {
+        // like a screenshot of text: four colors, in no pattern that a
+        // plain PNG compresses well
+        let mut next = noise();
+        let colors = [[255, 255, 255, 255], [0, 0, 0, 255], [200, 30, 30, 255], [30, 30, 200, 255]];
+        let screenshot = RgbaImage::from_fn(128, 128, |_, _| {
+            Rgba(colors[((next() >> 16) % 4).to_usize().unwrap()])
+        });
+        let original = png(screenshot.clone());
+        let image = compress(&original, &CompressOptions {
+            max_size: original.len() / 2, palette: true, ..options(false)
+        }).unwrap();
+        assert_eq!(image.mime, "image/png");
+        assert_eq!(image.info.quality, Some(MAX_QUALITY));
+        let decoded = image::load_from_memory(&image.data).unwrap().to_rgba8();
+        assert_eq!(decoded, screenshot);
+    }
+
+    fn animated_gif(frames: u32) -> Vec<u8> {
+        test_images::animated_gif((0..frames).map(|i| gradient(64, 64, i)), gif::Repeat::Infinite)
+    }
+
+    #[test]
+    fn keeps_animations() {
+        let original = animated_gif(8);
+        let gif = types(&["image/gif"]);
+        let image = compress(&original, &CompressOptions {
+            max_size: original.len() * 2, supported_types: gif.clone(), ..options(false)
+        }).unwrap();
+        assert_eq!(image.data, original);
+
+        let image = compress(&original, &CompressOptions {
+            max_size: original.len() / 2, supported_types: gif.clone(), ..options(false)
+        }).unwrap();
+        assert_eq!(image.mime, "image/gif");
+        assert!(image.data.len() < original.len() / 2);
+        let animation = animation::decode(ImageFormat::Gif, &image.data).unwrap().unwrap();
+        assert!(animation.frame_count() >= 3);
+
+        let image = compress(&original, &CompressOptions {
+            supported_types: gif, first_frame: true, ..options(false)
+        }).unwrap();
+        assert_eq!(image.mime, "image/jpeg");
+    }
+
+    #[test]
+    fn corrupt_animation_keeps_first_frame() {
+        // the third frame starts where the trailer of two frames is
+        let start = animated_gif(2).len() - 1;
+        let mut original = animated_gif(3);
+        // graphic control extension, then the image descriptor
+        assert_eq!(original[start..start + 2], [0x21, 0xf9]);
+        let at = start + 8;
+        assert_eq!(original[at], 0x2c);
+        let flags = original[at + 9];
+        let table = if flags & 0x80 == 0 { 0 } else { 3 << ((flags & 0x07) + 1) };
+        // an LZW code size no decoder accepts
+        original[at + 10 + table] = 12;
+        assert!(animation::decode(ImageFormat::Gif, &original).is_err());
+
+        let image = compress(&original, &CompressOptions {
+            supported_types: types(&["image/gif"]), ..options(false)
+        }).unwrap();
+        assert_eq!(image.mime, "image/jpeg");
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 64));
+    }
+}
@@ -0,0 +1,271 @@
+use std::io::Cursor;
+
+use color_quant::NeuQuant;
+use gif::Repeat;
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat};
+use num_traits::ToPrimitive;
+
+use super::metadata;
+use super::palette::{QUANTIZER_SAMPLING, palette_size};
+use super::{OutputFormat, scaled};
+
+/// Frames times canvas pixels decoded at most. Every frame is kept in full,
+/// so this takes 512 MB.
+const MAX_ANIMATION_PIXELS: u64 = 1 << 27;
+
+/// Scaled RGBA8 frames with their delays
+type ScaledFrames = Vec<(Vec<u8>, u32)>;
+
+/// Frames of an animated image, composited onto the full canvas.
+pub struct Animation {
+    /// RGBA8 frames with their delays, in milliseconds
+    frames: Vec<(DynamicImage, u32)>,
+    repeat: Repeat,
+}
+
+/// Decodes an animated GIF or WebP. Returns `None` for still images,
+/// including GIFs with a single frame, and fails for animations larger than
+/// `MAX_ANIMATION_PIXELS`.
+pub fn decode(format: ImageFormat, data: &[u8]) -> Result<Option<Animation>, String> {
+    let count = metadata::frame_count(format.to_mime_type(), data)
+        .ok_or("decode: cannot count frames".to_owned())?;
+    if count < 2 {
+        return Ok(None);
+    }
+    let check = |(width, height): (u32, u32)| {
+        let pixels = u64::from(width) * u64::from(height) * count as u64;
+        if pixels > MAX_ANIMATION_PIXELS {
+            return Err(format!("decode: {count} frames of {width}x{height} are too many"));
+        }
+        Ok(())
+    };
+    let (frames, repeat) = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(data))
+                .map_err(|e| format!("decode: {e}"))?;
+            check(decoder.dimensions())?;
+            (decoder.into_frames(), gif_repeat(data))
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data))
+                .map_err(|e| format!("decode: {e}"))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            check(decoder.dimensions())?;
+            (decoder.into_frames(), webp_repeat(data))
+        }
+        _ => return Ok(None),
+    };
+    let frames = frames.collect_frames().map_err(|e| format!("decode: {e}"))?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    let frames = frames.into_iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            (DynamicImage::ImageRgba8(frame.into_buffer()), numer / denom.max(1))
+        })
+        .collect();
+    Ok(Some(Animation { frames, repeat }))
+}
+
+/// Without a NETSCAPE2.0 extension, a GIF plays once.
+fn gif_repeat(data: &[u8]) -> Repeat {
+    match metadata::gif_loop_count(data) {
+        Some(0) => Repeat::Infinite,
+        Some(n) => Repeat::Finite(n),
+        None => Repeat::Finite(0),
+    }
+}
+
+fn webp_repeat(data: &[u8]) -> Repeat {
+    match metadata::webp_loop_count(data) {
+        Some(0) | None => Repeat::Infinite,
+        // WebP counts plays, GIF counts repetitions
+        Some(n) => Repeat::Finite(n - 1),
+    }
+}
+
+impl Animation {
+    pub fn width(&self) -> u32 {
+        self.frames[0].0.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.frames[0].0.height()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Scales every `step`-th frame, which takes the delays of the frames
+    /// that are dropped after it.
+    fn scaled_frames(&self, scaling: f64, step: usize) -> Result<(ScaledFrames, u32, u32), String> {
+        let mut size = (self.width(), self.height());
+        let mut frames = Vec::new();
+        for group in self.frames.chunks(step) {
+            let (buf, width, height) = scaled(&group[0].0, scaling)?;
+            size = (width, height);
+            frames.push((buf.into_owned(), group.iter().map(|f| f.1).sum()));
+        }
+        Ok((frames, size.0, size.1))
+    }
+
+    /// Encodes every `step`-th frame, scaled, in each of `formats` and
+    /// returns the smallest.
+    pub fn encode(
+        &self, scaling: f64, quality: u8, step: usize, formats: &[OutputFormat]
+    ) -> Result<(OutputFormat, Vec<u8>), String> {
+        let (frames, width, height) = self.scaled_frames(scaling, step)?;
+        let mut best: Option<(OutputFormat, Vec<u8>)> = None;
+        for &format in formats {
+            log::info!("compress_image: encoding {} frames as {format:?} at quality {quality}",
+                frames.len());
+            let data = match format {
+                OutputFormat::Gif => self.encode_gif(&frames, width, height, quality)?,
+                OutputFormat::WebpLossy => self.encode_webp(&frames, width, height, quality)?,
+                _ => return Err(format!("{format:?} cannot be animated")),
+            };
+            if best.as_ref().is_none_or(|(_, b)| data.len() < b.len()) {
+                best = Some((format, data));
+            }
+        }
+        best.ok_or("no output format".to_owned())
+    }
+
+    fn encode_gif(
+        &self, frames: &[(Vec<u8>, u32)], width: u32, height: u32, quality: u8
+    ) -> Result<Vec<u8>, String> {
+        let width = u16::try_from(width).map_err(|_| "too wide for GIF".to_owned())?;
+        let height = u16::try_from(height).map_err(|_| "too tall for GIF".to_owned())?;
+        let colors = palette_size(quality);
+
+        let mut out = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut out, width, height, &[])
+            .map_err(|e| format!("encode: {e}"))?;
+        encoder.set_repeat(self.repeat).map_err(|e| format!("encode: {e}"))?;
+        for (buf, delay) in frames {
+            let mut frame = quantize(buf, width, height, colors);
+            // in hundredths of a second
+            frame.delay = (delay / 10).to_u16().unwrap_or(u16::MAX);
+            // each frame covers the whole canvas
+            frame.dispose = gif::DisposalMethod::Background;
+            encoder.write_frame(&frame).map_err(|e| format!("encode: {e}"))?;
+        }
+        drop(encoder);
+        Ok(out)
+    }
+
+    /// The last frame lasts as long as the others on average, since the
+    /// encoder does not let us say otherwise.
+    fn encode_webp(
+        &self, frames: &[(Vec<u8>, u32)], width: u32, height: u32, quality: u8
+    ) -> Result<Vec<u8>, String> {
+        let mut config = webp::WebPConfig::new().map_err(|()| "WebPConfig".to_owned())?;
+        config.lossless = 0;
+        config.quality = f32::from(quality);
+        let mut encoder = webp::AnimEncoder::new(width, height, &config);
+        encoder.set_loop_count(match self.repeat {
+            Repeat::Infinite => 0,
+            Repeat::Finite(n) => i32::from(n) + 1,
+        });
+        let mut timestamp = 0;
+        for (buf, delay) in frames {
+            encoder.add_frame(webp::AnimFrame::from_rgba(buf, width, height, timestamp));
+            timestamp = timestamp.saturating_add(delay.to_i32().unwrap_or(i32::MAX));
+        }
+        let data = encoder.try_encode().map_err(|e| format!("encode: {e:?}"))?;
+        Ok(data.to_vec())
+    }
+}
+
+/// Builds an indexed frame with at most `colors` colors, one of which is
+/// transparent if any pixel is.
+fn quantize(rgba: &[u8], width: u16, height: u16, colors: usize) -> gif::Frame<'static> {
+    let transparent = rgba.chunks_exact(4).any(|p| p[3] < 128);
+    let quantizer = NeuQuant::new(
+        QUANTIZER_SAMPLING, if transparent { colors - 1 } else { colors }, rgba);
+    let mut palette = quantizer.color_map_rgb();
+    let key = (palette.len() / 3).to_u8();
+    let pixels: Vec<u8> = rgba.chunks_exact(4)
+        .map(|p| match key {
+            Some(key) if p[3] < 128 => key,
+            _ => quantizer.index_of(p).to_u8().unwrap(),
+        })
+        .collect();
+    let key = key.filter(|_| transparent);
+    if key.is_some() {
+        palette.extend([0, 0, 0]);
+    }
+    gif::Frame::from_palette_pixels(width, height, pixels, palette, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::super::{MAX_QUALITY, test_images};
+    use super::*;
+
+    /// Frames of a square moving over a transparent background.
+    fn animated_gif(frames: u32, repeat: Repeat) -> Vec<u8> {
+        test_images::animated_gif((0..frames).map(|i| RgbaImage::from_fn(40, 30, |x, y| {
+            if x / 10 == i % 4 && y < 10 { image::Rgba([255, 0, 0, 255]) } else { image::Rgba([0; 4]) }
+        })), repeat)
+    }
+
+    #[test]
+    fn keeps_timing_and_looping() {
+        let animation = decode(ImageFormat::Gif, &animated_gif(6, Repeat::Finite(2)))
+            .unwrap().unwrap();
+        assert_eq!(animation.frame_count(), 6);
+        assert_eq!(animation.repeat, Repeat::Finite(2));
+
+        for format in [OutputFormat::Gif, OutputFormat::WebpLossy] {
+            let (_, data) = animation.encode(0.5, MAX_QUALITY, 2, &[format]).unwrap();
+            let image_format = image::guess_format(&data).unwrap();
+            let decoded = decode(image_format, &data).unwrap().unwrap();
+            assert_eq!(decoded.frame_count(), 3, "{format:?}");
+            assert_eq!(decoded.repeat, Repeat::Finite(2), "{format:?}");
+            assert_eq!((decoded.width(), decoded.height()), (20, 15));
+            assert_eq!(decoded.frames[0].1, 200, "{format:?}");
+            // the background stays transparent
+            assert_eq!(decoded.frames[0].0.to_rgba8().get_pixel(19, 14)[3], 0, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn still_gif_is_not_an_animation() {
+        let data = animated_gif(1, Repeat::Infinite);
+        assert!(decode(ImageFormat::Gif, &data).unwrap().is_none());
+        assert_eq!(gif_repeat(&animated_gif(2, Repeat::Infinite)), Repeat::Infinite);
+    }
+
+    #[test]
+    fn finds_loop_counts_in_their_blocks() {
+        let gif = animated_gif(2, Repeat::Finite(3));
+        // a comment that looks like a looping extension, before the real one
+        let at = 13 + (3 << ((gif[10] & 0x07) + 1));
+        let mut decoy = gif[..at].to_vec();
+        decoy.extend(b"\x21\xfe\x0fNETSCAPE2.0\x03\x01\x07\x00\x00");
+        decoy.extend(&gif[at..]);
+        assert_eq!(gif_repeat(&decoy), Repeat::Finite(3));
+
+        let animation = decode(ImageFormat::Gif, &gif).unwrap().unwrap();
+        let (_, webp) = animation.encode(1.0, MAX_QUALITY, 1, &[OutputFormat::WebpLossy]).unwrap();
+        assert_eq!(&webp[30..34], b"ANIM");
+        // an unknown chunk between `VP8X` and `ANIM`
+        let mut extended = webp[..30].to_vec();
+        extended.extend(b"XTRA\x02\0\0\0ab");
+        extended.extend(&webp[30..]);
+        let size = u32::try_from(extended.len() - 8).unwrap();
+        extended[4..8].copy_from_slice(&size.to_le_bytes());
+        assert_eq!(webp_repeat(&webp), Repeat::Finite(3));
+        assert_eq!(webp_repeat(&extended), Repeat::Finite(3));
+    }
+
+}
@@ -0,0 +1,155 @@
+use std::num::NonZero;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use num_traits::ToPrimitive;
+use serde::Serialize;
+use tauri::State;
+use tauri::ipc::{Channel, Response};
+
+use crate::operation::{CancellationToken, Operations};
+
+use super::{CompressOptions, ImageSource, compress_source};
+
+/// Images compressed at the same time. Each one is decoded into memory in
+/// full, so this bounds the memory used as well.
+const MAX_WORKERS: usize = 4;
+
+#[derive(Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchReport {
+    compressed: usize,
+    failed: usize,
+    /// The remaining images were not processed
+    cancelled: bool,
+}
+
+/// Packs one message of `compress_images`: the index of the image and the
+/// numbers of images done and in total, then 0 and the packed image, or 1
+/// and the error message.
+fn pack_item(index: usize, done: usize, total: usize, result: Result<Vec<u8>, String>) -> Vec<u8> {
+    let mut buf: Vec<u8> = vec![];
+    buf.extend(index.to_u32().unwrap().to_le_bytes());
+    buf.extend(done.to_u32().unwrap().to_le_bytes());
+    buf.extend(total.to_u32().unwrap().to_le_bytes());
+    match result {
+        Ok(packed) => {
+            buf.push(0);
+            buf.extend(packed);
+        }
+        Err(e) => {
+            buf.push(1);
+            buf.extend(e.len().to_u32().unwrap().to_le_bytes());
+            buf.extend(e.as_bytes());
+        }
+    }
+    buf
+}
+
+/// Compresses the images from `sources` on a few threads, passing a message
+/// to `send` as each one is done. A failed image does not stop the others.
+fn compress_all(
+    sources: &[ImageSource], options: &CompressOptions, token: &CancellationToken,
+    send: &(dyn Fn(Vec<u8>) + Sync),
+) -> BatchReport {
+    let next = AtomicUsize::new(0);
+    let report = Mutex::new(BatchReport::default());
+    let workers = thread::available_parallelism()
+        .map_or(1, NonZero::get)
+        .min(MAX_WORKERS)
+        .min(sources.len());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(source) = sources.get(index) else { break };
+                if token.is_cancelled() {
+                    report.lock().unwrap().cancelled = true;
+                    break;
+                }
+                log::info!("compress_images: {source}");
+                let result = compress_source(source.clone(), options);
+
+                // sent while locked, so that the counts only go up
+                let mut report = report.lock().unwrap();
+                match &result {
+                    Ok(_) => report.compressed += 1,
+                    Err(e) => {
+                        log::warn!("compress_images: failed to compress {source}: {e}");
+                        report.failed += 1;
+                    }
+                }
+                let done = report.compressed + report.failed;
+                send(pack_item(index, done, sources.len(), result));
+            });
+        }
+    });
+    report.into_inner().unwrap()
+}
+
+/// Streams a message for each image over `channel`; see `pack_item`.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn compress_images(
+    channel: Channel<Response>, sources: Vec<ImageSource>, options: CompressOptions, id: String,
+    operations: State<'_, Arc<Operations>>,
+) -> Result<BatchReport, String> {
+    let operation = operations.register(id);
+    tauri::async_runtime::spawn_blocking(move || {
+        let send = |message| {
+            if let Err(e) = channel.send(Response::new(message)) {
+                log::warn!("compress_images: {e}");
+            }
+        };
+        compress_all(&sources, &options, operation.token(), &send)
+    })
+    .await
+    .map_err(|e| format!("tauri::async_runtime::spawn_blocking: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn reports_each_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sources = Vec::new();
+        for i in 0..5u8 {
+            let path = dir.path().join(format!("{i}.png"));
+            if i == 2 {
+                fs::write(&path, b"not an image").unwrap();
+            } else {
+                DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, image::Rgb([i; 3])))
+                    .save(&path).unwrap();
+            }
+            sources.push(ImageSource::Path(path.display().to_string()));
+        }
+        let options: CompressOptions = serde_json::from_value(serde_json::json!({
+            "maxSize": 1000, "maxWidth": 8, "supportedTypes": ["image/jpeg", "image/png"]
+        })).unwrap();
+
+        let messages = Mutex::new(Vec::new());
+        let send = |message: Vec<u8>| messages.lock().unwrap().push(message);
+        let report = compress_all(&sources, &options, &CancellationToken::default(), &send);
+        assert_eq!((report.compressed, report.failed), (4, 1));
+
+        let mut messages = messages.into_inner().unwrap();
+        let done: Vec<u8> = messages.iter().map(|m| m[4]).collect();
+        assert!(done.is_sorted(), "{done:?}");
+        messages.sort_by_key(|m| m[0]);
+        let status: Vec<(u8, u8)> = messages.iter().map(|m| (m[0], m[12])).collect();
+        assert_eq!(status, [(0, 0), (1, 0), (2, 1), (3, 0), (4, 0)]);
+
+        let token = CancellationToken::default();
+        token.cancel();
+        let report = compress_all(&sources, &options, &token, &|_| {});
+        assert_eq!(report.compressed + report.failed, 0);
+        assert!(report.cancelled);
+    }
+}
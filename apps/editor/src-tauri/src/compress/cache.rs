@@ -0,0 +1,120 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tempfile::NamedTempFile;
+
+/// Images on disk, named after a SHA-256 hash, in hex, of whatever they were
+/// made from. The least recently used ones are removed when the total size
+/// exceeds the capacity.
+pub struct ImageCache {
+    dir: PathBuf,
+    capacity: u64,
+    /// Held while entries are removed, since that lists the whole directory
+    lock: Mutex<()>,
+}
+
+impl ImageCache {
+    pub fn new(dir: PathBuf, capacity: u64) -> Self {
+        ImageCache { dir, capacity, lock: Mutex::new(()) }
+    }
+
+    /// Returns the entry and marks it as used.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.dir.join(key);
+        let data = fs::read(&path).ok()?;
+        // the modification time orders the entries by last use
+        let touched = File::options().write(true).open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+        if let Err(e) = touched {
+            log::warn!("image cache: cannot touch {key}: {e}");
+        }
+        Some(data)
+    }
+
+    pub fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // readers never see a partial entry
+        let mut file = NamedTempFile::new_in(&self.dir)?;
+        file.write_all(data)?;
+        file.persist(self.dir.join(key))?;
+        self.evict()
+    }
+
+    /// Entries with their size and time of last use. Skips the temporary
+    /// files of entries being written.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !name.to_str().is_some_and(|n| n.len() == 64 && n.bytes().all(|b| b.is_ascii_hexdigit())) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            entries.push((entry.path(), metadata.len(), metadata.modified()?));
+        }
+        Ok(entries)
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    fn evict(&self) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|e| e.1).sum();
+        if total <= self.capacity {
+            return Ok(());
+        }
+        entries.sort_by_key(|e| e.2);
+        for (path, size, _) in entries {
+            if total <= self.capacity {
+                break;
+            }
+            remove(&path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+/// Removes a file that another thread may have removed already.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::new(dir.path().join("images"), 25);
+        let key = |i: u8| format!("{:x}", Sha256::digest([i]));
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let age = |k: &str, t| File::options().write(true).open(dir.path().join("images").join(k))
+            .unwrap().set_modified(t).unwrap();
+
+        cache.put(&key(0), &[0; 10]).unwrap();
+        age(&key(0), at(1000));
+        cache.put(&key(1), &[1; 10]).unwrap();
+        age(&key(1), at(2000));
+        // now the most recently used
+        assert_eq!(cache.get(&key(0)), Some(vec![0; 10]));
+        cache.put(&key(2), &[2; 10]).unwrap();
+
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(2)).is_some());
+    }
+
+}
@@ -0,0 +1,232 @@
+//! Conversion to sRGB, which is what the encoders assume and what most
+//! readers display untagged images in.
+
+use image::{DynamicImage, RgbImage};
+use num_traits::ToPrimitive;
+use qcms::{DataType, Intent, Profile, Transform};
+use zune_jpeg::JpegDecoder;
+use zune_jpeg::zune_core::colorspace::ColorSpace;
+use zune_jpeg::zune_core::options::DecoderOptions;
+
+use super::metadata;
+
+/// The color space of an ICC profile, from its header.
+fn profile_space(icc: &[u8]) -> Option<&[u8]> {
+    icc.get(16..20)
+}
+
+/// Converts the pixels from `icc` to sRGB. Returns the image as RGB8 or RGBA8,
+/// and the profile back if it cannot be applied, so that the caller can store
+/// it with the image instead.
+pub fn to_srgb(img: &DynamicImage, icc: Option<Vec<u8>>) -> (DynamicImage, Option<Vec<u8>>) {
+    let alpha = img.color().has_alpha();
+    let mut img = if alpha {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+    let Some(icc) = icc else { return (img, None) };
+    if profile_space(&icc) != Some(b"RGB ") {
+        // a gray profile describes a gray image, which is now RGB
+        log::info!("compress_image: ignoring {:?} profile",
+            profile_space(&icc).map(String::from_utf8_lossy));
+        return (img, None);
+    }
+    let Some(profile) = Profile::new_from_slice(&icc, false) else {
+        log::warn!("compress_image: unreadable color profile");
+        return (img, Some(icc));
+    };
+    if profile.is_sRGB() {
+        return (img, None);
+    }
+    let mut srgb = Profile::new_sRGB();
+    srgb.precache_output_transform();
+    let ty = if alpha { DataType::RGBA8 } else { DataType::RGB8 };
+    let Some(transform) = Transform::new(&profile, &srgb, ty, Intent::Perceptual) else {
+        log::warn!("compress_image: unsupported color profile");
+        return (img, Some(icc));
+    };
+    match &mut img {
+        DynamicImage::ImageRgb8(buf) => transform.apply(buf),
+        DynamicImage::ImageRgba8(buf) => transform.apply(buf),
+        _ => unreachable!(),
+    }
+    (img, None)
+}
+
+/// JFIF YCbCr to RGB, for YCCK images.
+fn ycc_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (f32::from(y), f32::from(cb) - 128.0, f32::from(cr) - 128.0);
+    [
+        y + 1.402 * cr,
+        y - 0.344_136 * cb - 0.714_136 * cr,
+        y + 1.772 * cb,
+    ].map(|v| v.round().clamp(0.0, 255.0).to_u8().unwrap())
+}
+
+/// Decodes a CMYK or YCCK JPEG to sRGB, through its profile if it has one.
+/// Returns `None` for other JPEGs, which the `image` decoders handle.
+pub fn decode_cmyk_jpeg(data: &[u8], icc: Option<&[u8]>) -> Result<Option<DynamicImage>, String> {
+    let mut decoder = JpegDecoder::new(data);
+    decoder.decode_headers().map_err(|e| format!("decode: {e:?}"))?;
+    let Some(space @ (ColorSpace::CMYK | ColorSpace::YCCK)) = decoder.get_input_colorspace() else {
+        return Ok(None);
+    };
+    let (width, height) = decoder.dimensions().unwrap();
+    let options = DecoderOptions::default()
+        .set_strict_mode(false)
+        .jpeg_set_out_colorspace(space);
+    let mut cmyk = JpegDecoder::new_with_options(data, options)
+        .decode()
+        .map_err(|e| format!("decode: {e:?}"))?;
+
+    // Photoshop, which writes most of these, stores every channel inverted
+    // and says so with an Adobe marker. YCCK is only written that way.
+    let inverted = space == ColorSpace::YCCK || metadata::has_adobe_marker(data);
+    for px in cmyk.chunks_exact_mut(4) {
+        if space == ColorSpace::YCCK {
+            let [r, g, b] = ycc_to_rgb(px[0], px[1], px[2]);
+            // inverted CMY
+            px[..3].copy_from_slice(&[255 - r, 255 - g, 255 - b]);
+        }
+        if inverted {
+            for v in px {
+                *v = 255 - *v;
+            }
+        }
+    }
+
+    let mut rgb = vec![0; cmyk.len() / 4 * 3];
+    let transform = icc
+        .filter(|icc| profile_space(icc) == Some(b"CMYK"))
+        .and_then(|icc| Profile::new_from_slice(icc, false))
+        .and_then(|profile| {
+            let mut srgb = Profile::new_sRGB();
+            srgb.precache_output_transform();
+            Transform::new_to(&profile, &srgb, DataType::CMYK, DataType::RGB8, Intent::Perceptual)
+        });
+    if let Some(transform) = transform {
+        transform.convert(&cmyk, &mut rgb);
+    } else {
+        log::info!("compress_image: converting CMYK without a profile");
+        for (px, cmyk) in rgb.chunks_exact_mut(3).zip(cmyk.chunks_exact(4)) {
+            let k = 255 - u32::from(cmyk[3]);
+            for (v, c) in px.iter_mut().zip(&cmyk[..3]) {
+                *v = ((255 - u32::from(*c)) * k / 255).to_u8().unwrap();
+            }
+        }
+    }
+    let width = u32::try_from(width).map_err(|e| e.to_string())?;
+    let height = u32::try_from(height).map_err(|e| e.to_string())?;
+    let img = RgbImage::from_raw(width, height, rgb)
+        .ok_or("decode: wrong CMYK buffer size".to_owned())?;
+    Ok(Some(DynamicImage::ImageRgb8(img)))
+}
+
+#[cfg(test)]
+mod tests {
+    use jpeg_encoder::{ColorType, Encoder};
+
+    use super::*;
+
+    /// A matrix/TRC profile for an RGB space with the given primaries, as
+    /// D50 XYZ, and gamma 2.2.
+    fn rgb_profile(primaries: [[f64; 3]; 3]) -> Vec<u8> {
+        fn s15(v: f64) -> [u8; 4] {
+            (v * 65536.0).round().to_i32().unwrap().to_be_bytes()
+        }
+        fn xyz(v: [f64; 3]) -> Vec<u8> {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for c in v {
+                tag.extend(s15(c));
+            }
+            tag
+        }
+        let mut curve = b"curv\0\0\0\0".to_vec();
+        curve.extend(1u32.to_be_bytes());
+        curve.extend([0x02, 0x33, 0, 0]);
+        let white = [0.9642, 1.0, 0.8249];
+        let tags: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"rXYZ", xyz(primaries[0])), (b"gXYZ", xyz(primaries[1])),
+            (b"bXYZ", xyz(primaries[2])), (b"wtpt", xyz(white)),
+            (b"rTRC", curve.clone()), (b"gTRC", curve.clone()), (b"bTRC", curve),
+        ];
+
+        let mut header = vec![0; 128];
+        header[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        header[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        for (i, c) in white.iter().enumerate() {
+            header[68 + i * 4..72 + i * 4].copy_from_slice(&s15(*c));
+        }
+        let mut table = u32::try_from(tags.len()).unwrap().to_be_bytes().to_vec();
+        let mut data = Vec::new();
+        let mut at = 128 + 4 + tags.len() * 12;
+        for (sig, tag) in tags {
+            table.extend(sig);
+            table.extend(u32::try_from(at).unwrap().to_be_bytes());
+            table.extend(u32::try_from(tag.len()).unwrap().to_be_bytes());
+            at += tag.len();
+            data.extend(tag);
+        }
+        let mut icc = header;
+        icc.extend(table);
+        icc.extend(data);
+        let size = u32::try_from(icc.len()).unwrap().to_be_bytes();
+        icc[..4].copy_from_slice(&size);
+        icc
+    }
+
+    const SRGB_RED: [f64; 3] = [0.4361, 0.2225, 0.0139];
+    const SRGB_GREEN: [f64; 3] = [0.3851, 0.7169, 0.0971];
+    const SRGB_BLUE: [f64; 3] = [0.1431, 0.0606, 0.7141];
+
+    fn close(a: &[u8], b: &[u8]) -> bool {
+        a.iter().zip(b).all(|(a, b)| a.abs_diff(*b) <= 12)
+    }
+
+    #[test]
+    fn converts_through_profile() {
+        // red and green swapped, which no real profile would do
+        let icc = rgb_profile([SRGB_GREEN, SRGB_RED, SRGB_BLUE]);
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0])));
+        let (img, kept) = to_srgb(&img, Some(icc));
+        assert!(kept.is_none());
+        assert!(close(&img.to_rgb8().get_pixel(0, 0).0, &[0, 255, 0]), "{:?}", img.to_rgb8().get_pixel(0, 0));
+
+        let (_, kept) = to_srgb(&img, Some(b"not a profile".to_vec()));
+        assert!(kept.is_none());
+        let mut broken = rgb_profile([SRGB_RED, SRGB_GREEN, SRGB_BLUE]);
+        broken.truncate(200);
+        let (_, kept) = to_srgb(&img, Some(broken.clone()));
+        assert_eq!(kept, Some(broken));
+    }
+
+    fn cmyk_jpeg(color: ColorType, pixel: [u8; 4]) -> Vec<u8> {
+        let data: Vec<u8> = pixel.iter().copied().cycle().take(8 * 8 * 4).collect();
+        let mut out = Vec::new();
+        Encoder::new(&mut out, 100).encode(&data, 8, 8, color).unwrap();
+        out
+    }
+
+    #[test]
+    fn decodes_cmyk() {
+        // cyan, magenta and a dark yellow
+        for (cmyk, rgb) in [
+            ([255, 0, 0, 0], [0, 255, 255]),
+            ([0, 255, 0, 0], [255, 0, 255]),
+            ([0, 0, 255, 128], [127, 127, 0]),
+        ] {
+            for color in [ColorType::Cmyk, ColorType::CmykAsYcck] {
+                let data = cmyk_jpeg(color, cmyk);
+                let img = decode_cmyk_jpeg(&data, None).unwrap().unwrap();
+                let px = img.to_rgb8().get_pixel(3, 3).0;
+                assert!(close(&px, &rgb), "{cmyk:?} {color:?}: {px:?}");
+            }
+        }
+
+        let mut rgb = Vec::new();
+        Encoder::new(&mut rgb, 100).encode(&[0; 8 * 8 * 3], 8, 8, ColorType::Rgb).unwrap();
+        assert!(decode_cmyk_jpeg(&rgb, None).unwrap().is_none());
+    }
+}
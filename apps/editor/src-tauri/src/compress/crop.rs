@@ -0,0 +1,248 @@
+use std::fs;
+
+use fast_image_resize::{images::Image, IntoImageView, ResizeOptions, Resizer};
+use image::{DynamicImage, RgbImage, RgbaImage};
+use num_traits::ToPrimitive;
+use serde::Deserialize;
+use tauri::ipc::Response;
+
+use super::{
+    CompressedImage, Decoded, ImageInfo, MAX_QUALITY, OutputFormat, Search, decode,
+    default_max_iterations, default_min_quality, guess_format, keeps_profile, prepare_image,
+    respond, too_large, try_compress_size,
+};
+
+/// Part of an image, after its EXIF orientation is applied.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(tag = "unit", rename_all = "camelCase")]
+pub enum CropRect {
+    /// In source pixels
+    Pixels { x: f64, y: f64, width: f64, height: f64 },
+    /// As fractions of the source width and height
+    Normalized { x: f64, y: f64, width: f64, height: f64 },
+}
+
+/// What `crop` should produce.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CropOptions {
+    pub rect: CropRect,
+    /// Size of the result. The rectangle is narrowed around its center to
+    /// this aspect ratio; a smaller one is not enlarged to it.
+    pub width: u32,
+    pub height: u32,
+    /// `image/jpeg`, `image/png`, `image/webp` or `image/avif`
+    pub format: String,
+    /// Budget in bytes
+    pub max_size: usize,
+    /// Lowest quality that lossy encodings may go down to
+    #[serde(default = "default_min_quality")]
+    pub min_quality: u8,
+    /// Number of encodings to try at most
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: usize,
+    /// Color that transparent images are flattened onto for JPEG
+    #[serde(default = "default_background")]
+    pub background: [u8; 3],
+}
+
+fn default_background() -> [u8; 3] {
+    [255; 3]
+}
+
+/// Left, top, width and height in pixels
+type Area = (f64, f64, f64, f64);
+
+/// Resolves `rect` in an image of the given size, clipped to it.
+fn source_area(rect: CropRect, width: u32, height: u32) -> Result<Area, String> {
+    let (w, h) = (f64::from(width), f64::from(height));
+    let (x, y, cw, ch) = match rect {
+        CropRect::Pixels { x, y, width, height } => (x, y, width, height),
+        CropRect::Normalized { x, y, width, height } => (x * w, y * h, width * w, height * h),
+    };
+    let (left, top) = (x.clamp(0.0, w), y.clamp(0.0, h));
+    let (right, bottom) = ((x + cw).clamp(0.0, w), (y + ch).clamp(0.0, h));
+    // also catches NaN
+    if !(right - left >= 1.0 && bottom - top >= 1.0) {
+        return Err(format!("crop: {rect:?} is outside the {width} x {height} image"));
+    }
+    Ok((left, top, right - left, bottom - top))
+}
+
+/// Narrows `area` around its center to `aspect`, the width over the height.
+fn fit_aspect((left, top, width, height): Area, aspect: f64) -> Area {
+    if width / height > aspect {
+        let narrowed = height * aspect;
+        (left + (width - narrowed) / 2.0, top, narrowed, height)
+    } else {
+        let narrowed = width / aspect;
+        (left, top + (height - narrowed) / 2.0, width, narrowed)
+    }
+}
+
+/// Crops an encoded image, scales it to the requested size and encodes it
+/// in the requested format within `max_size` bytes, lowering the quality
+/// but not the size. For JPEG, transparent images are flattened onto the
+/// background color.
+pub fn crop(original: &[u8], options: &CropOptions) -> Result<CompressedImage, String> {
+    let format = OutputFormat::from_mime(&options.format)
+        .ok_or(format!("crop: cannot encode {}", options.format))?;
+    if options.width == 0 || options.height == 0 {
+        return Err("crop: empty target size".to_owned());
+    }
+    let Decoded { img, icc, .. } = decode(original, guess_format(original)?)?;
+    let background = (format == OutputFormat::Jpeg).then_some(options.background);
+    let (_, img) = prepare_image(&img, background);
+    let (original_width, original_height) = (img.width(), img.height());
+
+    let target = (f64::from(options.width), f64::from(options.height));
+    let area = fit_aspect(source_area(options.rect, img.width(), img.height())?, target.0 / target.1);
+    let (width, height) = if area.2 < target.0 {
+        (area.2.round().to_u32().unwrap().max(1), area.3.round().to_u32().unwrap().max(1))
+    } else {
+        (options.width, options.height)
+    };
+    log::info!("crop_image: {area:?} to {width} x {height}");
+    let mut dst = Image::new(width, height, img.pixel_type().unwrap());
+    Resizer::new()
+        .resize(&img, &mut dst, &ResizeOptions::new().crop(area.0, area.1, area.2, area.3))
+        .map_err(|e| format!("resize: {e}"))?;
+    let img = if img.color().has_alpha() {
+        RgbaImage::from_raw(width, height, dst.into_vec()).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(width, height, dst.into_vec()).map(DynamicImage::ImageRgb8)
+    }.ok_or("resize: wrong buffer size".to_owned())?;
+
+    let icc = icc.filter(|_| {
+        let keep = keeps_profile(format);
+        if !keep {
+            log::warn!("crop_image: dropping color profile for {format:?}");
+        }
+        keep
+    });
+    let formats = [format];
+    let encode = |scale, quality| try_compress_size(&img, scale, quality, &formats, icc.as_deref());
+    // a minimum scale of 1 leaves only the quality to search
+    let mut search = Search::new(&encode, 1.0, options.max_size, options.max_iterations);
+    let min_quality = if format.is_lossy() { options.min_quality.min(MAX_QUALITY) } else { MAX_QUALITY };
+    search.run(1.0, min_quality)?;
+    let Some(best) = search.best else {
+        return Err(too_large(search.smallest));
+    };
+    log::info!("crop_image: quality {}", best.quality);
+    let info = ImageInfo {
+        width, height, original_width, original_height,
+        quality: format.is_lossy().then_some(best.quality),
+        scale: f64::from(width) / area.2,
+        passthrough: false,
+    };
+    Ok(CompressedImage::new(best.format, best.data, info))
+}
+
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn crop_image(
+    path: String,
+    options: CropOptions
+) -> Result<Response, String> {
+    respond("crop_image", move || {
+        let original = fs::read(&path).map_err(|e| format!("fs::read: {e}"))?;
+        crop(&original, &options).map(CompressedImage::pack)
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::png::PngEncoder;
+
+    use super::*;
+
+    #[test]
+    fn resolves_areas() {
+        let pixels = CropRect::Pixels { x: 10.0, y: -5.0, width: 50.0, height: 40.0 };
+        assert_eq!(source_area(pixels, 40, 30), Ok((10.0, 0.0, 30.0, 30.0)));
+        let normalized = CropRect::Normalized { x: 0.25, y: 0.5, width: 0.5, height: 0.5 };
+        assert_eq!(source_area(normalized, 40, 30), Ok((10.0, 15.0, 20.0, 15.0)));
+        let outside = CropRect::Normalized { x: 1.0, y: 0.0, width: 0.5, height: f64::NAN };
+        assert!(source_area(outside, 40, 30).is_err());
+
+        assert_eq!(fit_aspect((0.0, 0.0, 40.0, 30.0), 1.0), (5.0, 0.0, 30.0, 30.0));
+        assert_eq!(fit_aspect((0.0, 0.0, 40.0, 30.0), 2.0), (0.0, 5.0, 40.0, 20.0));
+    }
+
+    /// Red on the left half, blue on the right.
+    fn halves() -> Vec<u8> {
+        let img = RgbImage::from_fn(200, 100, |x, _| {
+            if x < 100 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) }
+        });
+        let mut out = Vec::new();
+        DynamicImage::ImageRgb8(img).write_with_encoder(PngEncoder::new(&mut out)).unwrap();
+        out
+    }
+
+    fn options(rect: CropRect, width: u32, height: u32, format: &str) -> CropOptions {
+        CropOptions {
+            rect, width, height,
+            format: format.to_owned(),
+            max_size: 1 << 20,
+            min_quality: default_min_quality(),
+            max_iterations: default_max_iterations(),
+            background: default_background(),
+        }
+    }
+
+    #[test]
+    fn crops_to_size() {
+        let original = halves();
+        let right = CropRect::Normalized { x: 0.5, y: 0.0, width: 0.5, height: 1.0 };
+        let image = crop(&original, &options(right, 50, 50, "image/png")).unwrap();
+        assert_eq!(image.mime, "image/png");
+        let decoded = image::load_from_memory(&image.data).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (50, 50));
+        // the filter reaches a little past the edge
+        assert!(decoded.pixels().all(|p| p[0] < 32 && p[2] > 224));
+
+        // 2.35:1 out of 2:1 takes the middle
+        let all = CropRect::Pixels { x: 0.0, y: 0.0, width: 200.0, height: 100.0 };
+        let image = crop(&original, &options(all, 94, 40, "image/webp")).unwrap();
+        assert_eq!(image.mime, "image/webp");
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (94, 40));
+
+        // not enlarged
+        let small = CropRect::Pixels { x: 90.0, y: 0.0, width: 20.0, height: 20.0 };
+        let image = crop(&original, &options(small, 100, 100, "image/jpeg")).unwrap();
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (20, 20));
+        assert_eq!((image.info.original_width, image.info.scale), (200, 1.0));
+
+        let error = crop(&original, &CropOptions {
+            max_size: 10, ..options(all, 100, 100, "image/jpeg")
+        }).err().unwrap();
+        assert!(error.contains("the smallest result was"), "{error}");
+    }
+
+    #[test]
+    fn flattens_transparency_for_jpeg() {
+        // red, with a transparent black top left corner
+        let img = RgbaImage::from_fn(100, 100, |x, y| {
+            if x < 50 && y < 50 { image::Rgba([0; 4]) } else { image::Rgba([255, 0, 0, 255]) }
+        });
+        let mut original = Vec::new();
+        DynamicImage::ImageRgba8(img).write_with_encoder(PngEncoder::new(&mut original)).unwrap();
+        let all = CropRect::Normalized { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+
+        let image = crop(&original, &options(all, 100, 100, "image/jpeg")).unwrap();
+        assert_eq!(image.mime, "image/jpeg");
+        let decoded = image::load_from_memory(&image.data).unwrap().to_rgb8();
+        let corner = decoded.get_pixel(10, 10).0;
+        assert!(corner.iter().all(|&v| v > 240), "{corner:?}");
+
+        let image = crop(&original, &CropOptions {
+            background: [0, 0, 255], ..options(all, 100, 100, "image/jpeg")
+        }).unwrap();
+        let decoded = image::load_from_memory(&image.data).unwrap().to_rgb8();
+        let corner = decoded.get_pixel(10, 10).0;
+        assert!(corner[0] < 16 && corner[1] < 16 && corner[2] > 240, "{corner:?}");
+    }
+}
@@ -0,0 +1,470 @@
+//! Container-level editing of EXIF, XMP and IPTC data, so that images can be
+//! published without the location and camera details that phones embed.
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+const TAG_ARTIST: u16 = 0x013b;
+const TAG_COPYRIGHT: u16 = 0x8298;
+const TYPE_ASCII: u16 = 2;
+
+/// The EXIF fields that say who owns an image.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Copyright {
+    artist: Option<String>,
+    notice: Option<String>,
+}
+
+impl Copyright {
+    /// Reads the artist and copyright tags from an EXIF blob in TIFF layout,
+    /// as returned by the decoders. Returns `None` if it has neither.
+    pub fn from_exif(tiff: &[u8]) -> Option<Self> {
+        let big_endian = match tiff.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        let u16_at = |at: usize| -> Option<u16> {
+            let b: [u8; 2] = tiff.get(at..at + 2)?.try_into().ok()?;
+            Some(if big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+        };
+        let u32_at = |at: usize| -> Option<usize> {
+            let b: [u8; 4] = tiff.get(at..at + 4)?.try_into().ok()?;
+            let v = if big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) };
+            usize::try_from(v).ok()
+        };
+        let string_at = |entry: usize| -> Option<String> {
+            if u16_at(entry + 2)? != TYPE_ASCII {
+                return None;
+            }
+            let count = u32_at(entry + 4)?;
+            let at = if count <= 4 { entry + 8 } else { u32_at(entry + 8)? };
+            let raw = tiff.get(at..at.checked_add(count)?)?;
+            let value = String::from_utf8_lossy(raw);
+            let value = value.trim_end_matches('\0').trim();
+            (!value.is_empty()).then(|| value.to_string())
+        };
+
+        let ifd = u32_at(4)?;
+        let mut result = Copyright { artist: None, notice: None };
+        for i in 0..usize::from(u16_at(ifd)?) {
+            let entry = ifd + 2 + i * 12;
+            match u16_at(entry)? {
+                TAG_ARTIST => result.artist = string_at(entry),
+                TAG_COPYRIGHT => result.notice = string_at(entry),
+                _ => {}
+            }
+        }
+        (result.artist.is_some() || result.notice.is_some()).then_some(result)
+    }
+
+    /// Builds a little-endian TIFF blob holding only these fields.
+    pub fn to_exif(&self) -> Vec<u8> {
+        let fields: Vec<(u16, &str)> = [(TAG_ARTIST, &self.artist), (TAG_COPYRIGHT, &self.notice)]
+            .into_iter()
+            .filter_map(|(tag, value)| Some((tag, value.as_deref()?)))
+            .collect();
+
+        let mut out = b"II*\0".to_vec();
+        out.extend(8u32.to_le_bytes());
+        out.extend(u16::try_from(fields.len()).unwrap().to_le_bytes());
+        let mut data_at = 8 + 2 + fields.len() * 12 + 4;
+        let mut data = Vec::new();
+        for (tag, value) in &fields {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            out.extend(tag.to_le_bytes());
+            out.extend(TYPE_ASCII.to_le_bytes());
+            out.extend(u32::try_from(value.len()).unwrap().to_le_bytes());
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                out.extend(value);
+            } else {
+                out.extend(u32::try_from(data_at).unwrap().to_le_bytes());
+                data_at += value.len();
+                data.extend(value);
+            }
+        }
+        // no next IFD
+        out.extend(0u32.to_le_bytes());
+        out.extend(data);
+        out
+    }
+}
+
+/// Removes all EXIF, XMP and IPTC data from an encoded image, then stores
+/// `exif` in it if given. Returns `None` for formats it cannot edit, or if
+/// the image is malformed.
+pub fn rewrite(mime: &str, data: &[u8], exif: Option<&[u8]>) -> Option<Vec<u8>> {
+    match mime {
+        "image/jpeg" => rewrite_jpeg(data, exif),
+        "image/png" => rewrite_png(data, exif),
+        "image/webp" => rewrite_webp(data, exif),
+        // GIF has no place for EXIF
+        "image/gif" if exif.is_none() => rewrite_gif(data),
+        _ => None,
+    }
+}
+
+/// JPEG segments as marker and bytes, including the marker
+type Segments<'a> = Vec<(u8, &'a [u8])>;
+
+/// Splits a JPEG into the segments before the first scan, and the offset of
+/// the scan.
+fn jpeg_segments(data: &[u8]) -> Option<(Segments<'_>, usize)> {
+    if data.get(..2)? != b"\xff\xd8" {
+        return None;
+    }
+    let mut segments = Vec::new();
+    let mut at = 2;
+    loop {
+        if *data.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(at + 1)?;
+        if marker == 0xff {
+            // fill byte
+            at += 1;
+            continue;
+        }
+        if marker == 0xda {
+            // start of scan: the rest is entropy-coded data
+            return Some((segments, at));
+        }
+        let len = usize::from(u16::from_be_bytes(data.get(at + 2..at + 4)?.try_into().ok()?));
+        let end = at + 2 + len;
+        segments.push((marker, data.get(at..end)?));
+        at = end;
+    }
+}
+
+/// Whether a JPEG has the APP14 segment that Adobe software writes, which
+/// means its CMYK channels are stored inverted.
+pub fn has_adobe_marker(data: &[u8]) -> bool {
+    jpeg_segments(data).is_some_and(|(segments, _)| {
+        segments.iter().any(|(marker, segment)| *marker == 0xee && segment.get(4..9) == Some(b"Adobe"))
+    })
+}
+
+/// Drops the APP1 (EXIF, XMP) and APP13 (IPTC) segments, keeping the ones
+/// that affect how the image looks, such as ICC profiles in APP2.
+fn rewrite_jpeg(data: &[u8], exif: Option<&[u8]>) -> Option<Vec<u8>> {
+    let (segments, scan) = jpeg_segments(data)?;
+    let mut out = data[..2].to_vec();
+    let mut exif = exif;
+    let mut insert_exif = |out: &mut Vec<u8>| {
+        if let Some(exif) = exif.take() {
+            let len = u16::try_from(2 + EXIF_HEADER.len() + exif.len()).ok()?;
+            out.extend([0xff, 0xe1]);
+            out.extend(len.to_be_bytes());
+            out.extend(EXIF_HEADER);
+            out.extend(exif);
+        }
+        Some(())
+    };
+    for (marker, segment) in segments {
+        // EXIF goes after JFIF, which must come first
+        if marker != 0xe0 {
+            insert_exif(&mut out)?;
+        }
+        if !matches!(marker, 0xe1 | 0xed) {
+            out.extend(segment);
+        }
+    }
+    insert_exif(&mut out)?;
+    out.extend(&data[scan..]);
+    Some(out)
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) -> Option<()> {
+    out.extend(u32::try_from(data.len()).ok()?.to_be_bytes());
+    out.extend(kind);
+    out.extend(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend(crc.finalize().to_be_bytes());
+    Some(())
+}
+
+/// Drops `eXIf` and the text chunks, where XMP and IPTC are kept as well.
+fn rewrite_png(data: &[u8], exif: Option<&[u8]>) -> Option<Vec<u8>> {
+    if data.get(..8)? != PNG_SIGNATURE {
+        return None;
+    }
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut at = 8;
+    while at < data.len() {
+        let len = usize::try_from(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?)).ok()?;
+        let kind = data.get(at + 4..at + 8)?;
+        let end = at + 12 + len;
+        let chunk = data.get(at..end)?;
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+            out.extend(chunk);
+        }
+        if kind == b"IHDR" && let Some(exif) = exif {
+            png_chunk(&mut out, b"eXIf", exif)?;
+        }
+        at = end;
+    }
+    Some(out)
+}
+
+/// Width, height and whether there is alpha, from a `VP8 ` or `VP8L` chunk.
+fn webp_canvas(kind: &[u8], data: &[u8]) -> Option<(u32, u32, bool)> {
+    match kind {
+        b"VP8 " => {
+            let w = u16::from_le_bytes(data.get(6..8)?.try_into().ok()?) & 0x3fff;
+            let h = u16::from_le_bytes(data.get(8..10)?.try_into().ok()?) & 0x3fff;
+            Some((u32::from(w), u32::from(h), false))
+        }
+        b"VP8L" => {
+            if *data.first()? != 0x2f {
+                return None;
+            }
+            let bits = u32::from_le_bytes(data.get(1..5)?.try_into().ok()?);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1, bits >> 28 & 1 == 1))
+        }
+        _ => None,
+    }
+}
+
+/// Splits a WebP file into the kinds and bodies of its chunks.
+fn webp_chunks(data: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut at = 12;
+    while at < data.len() {
+        let kind = data.get(at..at + 4)?;
+        let len = usize::try_from(u32::from_le_bytes(data.get(at + 4..at + 8)?.try_into().ok()?)).ok()?;
+        chunks.push((kind, data.get(at + 8..at + 8 + len)?));
+        at += 8 + len + len % 2;
+    }
+    Some(chunks)
+}
+
+/// Drops the `EXIF` and `XMP ` chunks. Only the extended format can hold
+/// EXIF, so a simple file is converted to it when `exif` is given.
+fn rewrite_webp(data: &[u8], exif: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut chunks: Vec<(&[u8], Vec<u8>)> = webp_chunks(data)?.into_iter()
+        .filter(|(kind, _)| !matches!(*kind, b"EXIF" | b"XMP "))
+        .map(|(kind, body)| (kind, body.to_vec()))
+        .collect();
+
+    if chunks.first()?.0 != b"VP8X" && exif.is_some() {
+        let (width, height, alpha) = webp_canvas(chunks[0].0, &chunks[0].1)?;
+        let mut header = vec![if alpha { 0x10 } else { 0 }, 0, 0, 0];
+        header.extend(&(width - 1).to_le_bytes()[..3]);
+        header.extend(&(height - 1).to_le_bytes()[..3]);
+        chunks.insert(0, (b"VP8X", header));
+    }
+    if chunks[0].0 == b"VP8X" {
+        let flags = chunks[0].1.first_mut()?;
+        // EXIF and XMP bits
+        *flags &= !0x0c;
+        if exif.is_some() {
+            *flags |= 0x08;
+        }
+    }
+    if let Some(exif) = exif {
+        chunks.push((b"EXIF", exif.to_vec()));
+    }
+
+    let mut out = b"RIFF\0\0\0\0WEBP".to_vec();
+    for (kind, body) in chunks {
+        out.extend(kind);
+        out.extend(u32::try_from(body.len()).ok()?.to_le_bytes());
+        out.extend(&body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+    let size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(out)
+}
+
+/// Returns where the data sub-blocks starting at `at` end.
+fn gif_sub_blocks(data: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = usize::from(*data.get(at)?);
+        at += 1 + len;
+        if len == 0 {
+            return Some(at);
+        }
+    }
+}
+
+/// A block of a GIF, from its introducer to its last byte.
+enum GifBlock<'a> {
+    /// An extension with its label
+    Extension(u8, &'a [u8]),
+    Image(&'a [u8]),
+}
+
+/// Splits a GIF into its header, with the global color table, and the blocks
+/// before the trailer.
+fn gif_blocks(data: &[u8]) -> Option<(&[u8], Vec<GifBlock<'_>>)> {
+    if !matches!(data.get(..6)?, b"GIF87a" | b"GIF89a") {
+        return None;
+    }
+    let flags = *data.get(10)?;
+    let mut at = 13;
+    if flags & 0x80 != 0 {
+        at += 3 << ((flags & 0x07) + 1);
+    }
+    let header = data.get(..at)?;
+    let mut blocks = Vec::new();
+    loop {
+        let start = at;
+        match *data.get(at)? {
+            0x21 => {
+                let label = *data.get(at + 1)?;
+                at = gif_sub_blocks(data, at + 2)?;
+                blocks.push(GifBlock::Extension(label, data.get(start..at)?));
+            }
+            0x2c => {
+                let flags = *data.get(at + 9)?;
+                at += 10;
+                if flags & 0x80 != 0 {
+                    at += 3 << ((flags & 0x07) + 1);
+                }
+                // LZW code size, then the image data
+                at = gif_sub_blocks(data, at + 1)?;
+                blocks.push(GifBlock::Image(data.get(start..at)?));
+            }
+            0x3b => return Some((header, blocks)),
+            _ => return None,
+        }
+    }
+}
+
+/// Drops comments and application extensions, where XMP is kept, except the
+/// ones for looping and for the ICC profile.
+fn rewrite_gif(data: &[u8]) -> Option<Vec<u8>> {
+    const KEPT_APPS: [&[u8]; 3] = [b"NETSCAPE2.0", b"ANIMEXTS1.0", b"ICCRGBG1012"];
+    let (header, blocks) = gif_blocks(data)?;
+    let mut out = header.to_vec();
+    for block in blocks {
+        match block {
+            GifBlock::Extension(0xfe, _) => {}
+            GifBlock::Extension(0xff, ext)
+                if !ext.get(3..14).is_some_and(|app| KEPT_APPS.contains(&app)) => {}
+            GifBlock::Extension(_, bytes) | GifBlock::Image(bytes) => out.extend(bytes),
+        }
+    }
+    out.push(0x3b);
+    Some(out)
+}
+
+/// Counts the frames of a GIF or WebP, and returns 1 for other formats.
+/// Returns `None` if the image is malformed.
+pub fn frame_count(mime: &str, data: &[u8]) -> Option<usize> {
+    match mime {
+        "image/gif" => {
+            let (_, blocks) = gif_blocks(data)?;
+            Some(blocks.iter().filter(|b| matches!(b, GifBlock::Image(_))).count())
+        }
+        // a still image has no `ANMF` chunk
+        "image/webp" => Some(webp_chunks(data)?.iter().filter(|c| c.0 == b"ANMF").count().max(1)),
+        _ => Some(1),
+    }
+}
+
+/// Reads the loop count of the NETSCAPE2.0 application extension of a GIF,
+/// where 0 means forever.
+pub fn gif_loop_count(data: &[u8]) -> Option<u16> {
+    gif_blocks(data)?.1.into_iter().find_map(|block| match block {
+        GifBlock::Extension(0xff, ext) if ext.get(2..14) == Some(&b"\x0bNETSCAPE2.0"[..]) =>
+            match ext.get(14..18)? {
+                &[3, 1, lo, hi] => Some(u16::from_le_bytes([lo, hi])),
+                _ => None,
+            },
+        _ => None,
+    })
+}
+
+/// Reads the loop count of the `ANIM` chunk of a WebP, where 0 means forever.
+pub fn webp_loop_count(data: &[u8]) -> Option<u16> {
+    let (_, body) = webp_chunks(data)?.into_iter().find(|c| c.0 == b"ANIM")?;
+    Some(u16::from_le_bytes(body.get(4..6)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, RgbaImage};
+
+    use super::*;
+
+    fn copyright() -> Copyright {
+        Copyright { artist: Some("Someone".into()), notice: Some("CC BY 4.0".into()) }
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let img = RgbaImage::from_fn(8, 6, |x, y| image::Rgba([0, 0, 0, if x + y > 4 { 255 } else { 0 }]));
+        let img = if format == ImageFormat::Jpeg {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).into_rgb8())
+        } else {
+            DynamicImage::ImageRgba8(img)
+        };
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    /// The PNG decoder does not read EXIF, so this also looks for the blob.
+    fn read_exif(data: &[u8], exif: &[u8]) -> Option<Vec<u8>> {
+        let reader = ImageReader::new(Cursor::new(data)).with_guessed_format().unwrap();
+        let mut decoder = reader.into_decoder().unwrap();
+        let read = decoder.exif_metadata().unwrap();
+        // the pixels must survive
+        DynamicImage::from_decoder(decoder).unwrap();
+        let found = data.windows(exif.len()).any(|w| w == exif);
+        read.or_else(|| found.then(|| exif.to_vec()))
+    }
+
+    #[test]
+    fn exif_roundtrip() {
+        let exif = copyright().to_exif();
+        assert_eq!(Copyright::from_exif(&exif), Some(copyright()));
+        let short = Copyright { artist: Some("Me".into()), notice: None };
+        assert_eq!(Copyright::from_exif(&short.to_exif()), Some(short));
+        assert_eq!(Copyright::from_exif(b"II*\0\x08\0\0\0\0\0\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn rewrites_containers() {
+        let exif = copyright().to_exif();
+        for (mime, format) in [
+            ("image/jpeg", ImageFormat::Jpeg),
+            ("image/png", ImageFormat::Png),
+            ("image/webp", ImageFormat::WebP),
+        ] {
+            let with = rewrite(mime, &encode(format), Some(&exif)).unwrap();
+            assert_eq!(read_exif(&with, &exif).as_deref(), Some(exif.as_slice()), "{mime}");
+            let without = rewrite(mime, &with, None).unwrap();
+            assert_eq!(read_exif(&without, &exif), None, "{mime}");
+            assert!(without.len() < with.len(), "{mime}");
+        }
+        assert_eq!(rewrite("image/avif", b"", None), None);
+    }
+
+    #[test]
+    fn strips_gif_extensions() {
+        let gif = encode(ImageFormat::Gif);
+        // insert a comment and an XMP extension after the header
+        let at = 13 + (3 << ((gif[10] & 0x07) + 1));
+        let mut tagged = gif[..at].to_vec();
+        tagged.extend(b"\x21\xfe\x05hello\x00");
+        tagged.extend(b"\x21\xff\x0bXMP DataXMP\x03abc\x00");
+        tagged.extend(&gif[at..]);
+
+        let stripped = rewrite("image/gif", &tagged, None).unwrap();
+        assert_eq!(stripped, gif);
+        assert_eq!(rewrite("image/gif", &gif, Some(b"II*\0")), None);
+    }
+}
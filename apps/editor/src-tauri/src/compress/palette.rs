@@ -0,0 +1,147 @@
+//! Indexed colors, for GIF frames and for PNGs of flat graphics.
+
+use std::collections::{HashMap, HashSet};
+
+use color_quant::NeuQuant;
+use image::ExtendedColorType;
+use num_traits::ToPrimitive;
+use png::{BitDepth, ColorType, Compression, Encoder, FilterType};
+
+use super::MAX_QUALITY;
+
+/// 1-30, where 1 gives the best palettes. 10 is what `gif` uses by default.
+pub const QUANTIZER_SAMPLING: i32 = 10;
+
+/// Palette size for indexed images, halved for every 10 points of quality
+/// below the maximum.
+pub fn palette_size(quality: u8) -> usize {
+    let halvings = (MAX_QUALITY - quality.min(MAX_QUALITY)) / 10;
+    256 >> halvings.min(4)
+}
+
+/// The colors of `rgba`, if there are at most `colors` of them, which is
+/// the case for most screenshots and diagrams.
+fn exact_palette(rgba: &[u8], colors: usize) -> Option<Vec<[u8; 4]>> {
+    let mut seen: HashSet<[u8; 4]> = HashSet::new();
+    for p in rgba.chunks_exact(4) {
+        seen.insert(p.try_into().unwrap());
+        if seen.len() > colors {
+            return None;
+        }
+    }
+    let mut palette: Vec<[u8; 4]> = seen.into_iter().collect();
+    // transparent entries first, so that the tRNS chunk stays short
+    palette.sort_by_key(|c| (c[3] == 255, *c));
+    Some(palette)
+}
+
+/// Encodes RGB8 or RGBA8 pixels as a PNG with at most `colors` colors. An
+/// image that has no more than that is kept exactly; others are quantized.
+pub fn encode_png(
+    buf: &[u8], width: u32, height: u32, color: ExtendedColorType, colors: usize
+) -> Result<Vec<u8>, String> {
+    let rgba: Vec<u8> = if color == ExtendedColorType::Rgba8 {
+        buf.to_vec()
+    } else {
+        buf.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect()
+    };
+    let (palette, indices): (Vec<[u8; 4]>, Vec<u8>) = if let Some(palette) = exact_palette(&rgba, colors) {
+        let index: HashMap<[u8; 4], u8> = palette.iter()
+            .enumerate()
+            .map(|(i, c)| (*c, i.to_u8().unwrap()))
+            .collect();
+        let indices = rgba.chunks_exact(4).map(|p| index[p]).collect();
+        (palette, indices)
+    } else {
+        let quantizer = NeuQuant::new(QUANTIZER_SAMPLING, colors, &rgba);
+        let palette = quantizer.color_map_rgba()
+            .chunks_exact(4)
+            .map(|c| c.try_into().unwrap())
+            .collect();
+        let indices = rgba.chunks_exact(4)
+            .map(|p| quantizer.index_of(p).to_u8().unwrap())
+            .collect();
+        (palette, indices)
+    };
+
+    let (depth, bits) = match palette.len() {
+        0..=2 => (BitDepth::One, 1),
+        3..=4 => (BitDepth::Two, 2),
+        5..=16 => (BitDepth::Four, 4),
+        _ => (BitDepth::Eight, 8),
+    };
+    // rows start on a byte
+    let per_byte = 8 / bits;
+    let mut packed = Vec::new();
+    for row in indices.chunks_exact(width.to_usize().unwrap()) {
+        for group in row.chunks(per_byte) {
+            let mut byte = 0;
+            for (i, index) in group.iter().enumerate() {
+                byte |= index << (8 - bits * (i + 1));
+            }
+            packed.push(byte);
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(&mut out, width, height);
+    encoder.set_color(ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_compression(Compression::Best);
+    // neighboring indices are unrelated, so filters only get in the way
+    encoder.set_filter(FilterType::NoFilter);
+    encoder.set_palette(palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect::<Vec<u8>>());
+    let opaque = palette.iter().rposition(|c| c[3] != 255).map_or(0, |i| i + 1);
+    if opaque > 0 {
+        encoder.set_trns(palette[..opaque].iter().map(|c| c[3]).collect::<Vec<u8>>());
+    }
+    let mut writer = encoder.write_header().map_err(|e| format!("encode: {e}"))?;
+    writer.write_image_data(&packed).map_err(|e| format!("encode: {e}"))?;
+    writer.finish().map_err(|e| format!("encode: {e}"))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{RgbImage, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn smaller_palettes() {
+        assert_eq!(palette_size(MAX_QUALITY), 256);
+        assert_eq!(palette_size(MAX_QUALITY - 10), 128);
+        assert_eq!(palette_size(0), 16);
+    }
+
+    fn roundtrip(img: &RgbaImage, colors: usize) -> RgbaImage {
+        let data = encode_png(img.as_raw(), img.width(), img.height(), ExtendedColorType::Rgba8, colors)
+            .unwrap();
+        image::load_from_memory(&data).unwrap().to_rgba8()
+    }
+
+    #[test]
+    fn keeps_few_colors_exactly() {
+        // three colors and a transparent corner, 5 pixels wide to pad the rows
+        let img = RgbaImage::from_fn(5, 4, |x, y| image::Rgba(match (x, y) {
+            (0, 0) => [0, 0, 0, 0],
+            _ if x < 2 => [255, 0, 0, 255],
+            _ if y < 2 => [0, 128, 0, 255],
+            _ => [10, 20, 30, 128],
+        }));
+        assert_eq!(roundtrip(&img, 16), img);
+
+        let gradient = RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([(x * 4).to_u8().unwrap(), (y * 4).to_u8().unwrap(), 0, 255])
+        });
+        let quantized = roundtrip(&gradient, 16);
+        assert_eq!(quantized.dimensions(), (64, 64));
+        assert!(quantized.pixels().collect::<HashSet<_>>().len() <= 16);
+
+        let rgb = RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16).to_u8().unwrap(), (y * 16).to_u8().unwrap(), 0])
+        });
+        let data = encode_png(rgb.as_raw(), 16, 16, ExtendedColorType::Rgb8, 256).unwrap();
+        assert_eq!(image::load_from_memory(&data).unwrap().to_rgb8(), rgb);
+    }
+}
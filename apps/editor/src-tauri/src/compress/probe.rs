@@ -0,0 +1,124 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+
+use image::metadata::Orientation;
+use image::{ImageDecoder, ImageFormat, ImageReader};
+use serde::Serialize;
+
+use super::metadata;
+
+/// What an image is, read from its headers.
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageProbe {
+    pub mime: &'static str,
+    /// After the EXIF orientation is applied
+    pub width: u32,
+    pub height: u32,
+    /// Of the file, in bytes
+    pub size: u64,
+    pub alpha: bool,
+    /// 1 for still images
+    pub frames: usize,
+}
+
+/// The probe of one image, or why it failed.
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum ProbeResult {
+    Ok(ImageProbe),
+    Failed { error: String },
+}
+
+/// Reads the metadata of the image at `path` without decoding its pixels.
+/// GIF and WebP are read in full to count their frames, which are spread
+/// through the file.
+fn probe(path: &str) -> Result<ImageProbe, String> {
+    let file = File::open(path).map_err(|e| format!("open: {e}"))?;
+    let size = file.metadata().map_err(|e| format!("metadata: {e}"))?.len();
+    let reader = ImageReader::new(BufReader::new(file))
+        .with_guessed_format()
+        .map_err(|e| format!("with_guessed_format: {e}"))?;
+    let format = reader.format().ok_or("with_guessed_format: cannot guess format".to_owned())?;
+    let mut decoder = reader.into_decoder().map_err(|e| format!("decode: {e}"))?;
+    let (mut width, mut height) = decoder.dimensions();
+    if matches!(
+        decoder.orientation().unwrap_or(Orientation::NoTransforms),
+        Orientation::Rotate90 | Orientation::Rotate270
+            | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH
+    ) {
+        (width, height) = (height, width);
+    }
+    let alpha = decoder.color_type().has_alpha();
+    let mime = format.to_mime_type();
+    let frames = if matches!(format, ImageFormat::Gif | ImageFormat::WebP) {
+        let data = fs::read(path).map_err(|e| format!("fs::read: {e}"))?;
+        metadata::frame_count(mime, &data).ok_or("probe: cannot count frames".to_owned())?
+    } else {
+        1
+    };
+    Ok(ImageProbe { mime, width, height, size, alpha, frames })
+}
+
+/// Probes every image in `paths`; a failed one does not stop the others.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn probe_image(paths: Vec<String>) -> Result<Vec<ProbeResult>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        paths.iter()
+            .map(|path| match probe(path) {
+                Ok(probe) => ProbeResult::Ok(probe),
+                Err(error) => {
+                    log::warn!("probe_image: {path}: {error}");
+                    ProbeResult::Failed { error }
+                }
+            })
+            .collect()
+    })
+    .await
+    .map_err(|e| format!("tauri::async_runtime::spawn_blocking: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use gif::Repeat;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{DynamicImage, RgbaImage};
+
+    use super::super::test_images::{animated_gif, gradient};
+    use super::super::{OutputFormat, animation};
+    use super::*;
+
+    #[test]
+    fn probes_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).display().to_string();
+
+        DynamicImage::ImageRgba8(RgbaImage::new(30, 20)).save(path("a.png")).unwrap();
+        let png = probe(&path("a.png")).unwrap();
+        assert_eq!((png.mime, png.width, png.height, png.alpha, png.frames),
+            ("image/png", 30, 20, true, 1));
+        assert_eq!(png.size, fs::metadata(path("a.png")).unwrap().len());
+
+        // IFD0 with Orientation = 6, i.e. rotate 90 degrees clockwise
+        let exif = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0";
+        let mut jpeg = Vec::new();
+        DynamicImage::new_rgb8(8, 4).write_with_encoder(JpegEncoder::new(&mut jpeg)).unwrap();
+        fs::write(path("b.jpg"), metadata::rewrite("image/jpeg", &jpeg, Some(exif)).unwrap()).unwrap();
+        let jpeg = probe(&path("b.jpg")).unwrap();
+        assert_eq!((jpeg.width, jpeg.height, jpeg.alpha), (4, 8, false));
+
+        let gif = animated_gif((0..5).map(|i| gradient(12, 10, i)), Repeat::Infinite);
+        fs::write(path("c.gif"), &gif).unwrap();
+        assert_eq!(probe(&path("c.gif")).unwrap().frames, 5);
+        let animation = animation::decode(ImageFormat::Gif, &gif).unwrap().unwrap();
+        let (_, webp) = animation.encode(1.0, 80, 1, &[OutputFormat::WebpLossy]).unwrap();
+        fs::write(path("d.webp"), webp).unwrap();
+        let webp = probe(&path("d.webp")).unwrap();
+        assert_eq!((webp.mime, webp.width, webp.height, webp.frames), ("image/webp", 12, 10, 5));
+
+        fs::write(path("e.png"), b"not an image").unwrap();
+        assert!(probe(&path("e.png")).is_err());
+        assert!(probe(&path("missing.png")).is_err());
+    }
+}
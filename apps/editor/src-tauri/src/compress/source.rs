@@ -0,0 +1,135 @@
+use std::fs;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tauri::ipc::{InvokeBody, Request};
+use tauri_plugin_http::reqwest::{self, Client};
+
+use super::CompressOptions;
+
+/// Largest image fetched from a URL, in bytes
+const MAX_DOWNLOAD_SIZE: u64 = 50 << 20;
+/// Time a download may take, connecting included
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// Header that carries the options of `compress_image` when the image is
+/// sent as the raw body
+const OPTIONS_HEADER: &str = "compress-options";
+
+/// Where an image to compress comes from.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageSource {
+    Path(String),
+    /// `http:` or `https:`
+    Url(String),
+    /// Sent by the frontend as the body of the request
+    #[serde(skip)]
+    Bytes(Vec<u8>),
+}
+
+impl ImageSource {
+    pub fn read(self) -> Result<Vec<u8>, String> {
+        match self {
+            ImageSource::Path(path) => fs::read(path).map_err(|e| format!("fs::read: {e}")),
+            ImageSource::Url(url) => fetch(&url, MAX_DOWNLOAD_SIZE),
+            ImageSource::Bytes(data) => Ok(data),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageSource::Path(path) => write!(f, "{path}"),
+            ImageSource::Url(url) => write!(f, "{url}"),
+            ImageSource::Bytes(data) => write!(f, "<{} bytes>", data.len()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Arguments {
+    source: ImageSource,
+    options: CompressOptions,
+}
+
+/// Reads the arguments of `compress_image`: either the image as the raw body
+/// and the options as JSON in a header, or both as JSON.
+pub fn arguments(request: &Request<'_>) -> Result<(ImageSource, CompressOptions), String> {
+    match request.body() {
+        InvokeBody::Raw(data) => {
+            let options = request.headers().get(OPTIONS_HEADER)
+                .ok_or(format!("compress_image: no {OPTIONS_HEADER} header"))?;
+            let options = serde_json::from_slice(options.as_bytes())
+                .map_err(|e| format!("compress_image: {OPTIONS_HEADER}: {e}"))?;
+            Ok((ImageSource::Bytes(data.clone()), options))
+        }
+        InvokeBody::Json(value) => {
+            let args = Arguments::deserialize(value)
+                .map_err(|e| format!("compress_image: {e}"))?;
+            Ok((args.source, args.options))
+        }
+    }
+}
+
+/// Downloads `url` into memory, failing if it is larger than `max_size`.
+fn fetch(url: &str, max_size: u64) -> Result<Vec<u8>, String> {
+    if !(url.starts_with("http:") || url.starts_with("https:")) {
+        return Err(format!("fetch: not an http(s) URL: {url}"));
+    }
+    let client = Client::builder()
+        .connect_timeout(DOWNLOAD_TIMEOUT)
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(|e| format!("fetch: {e}"))?;
+    tauri::async_runtime::block_on(async {
+        let failed = |e: reqwest::Error| format!("fetch: {e}");
+        let mut response = client.get(url).send().await.map_err(failed)?
+            .error_for_status().map_err(failed)?;
+        let too_large = || format!("fetch: image is larger than the limit of {max_size} bytes");
+        if response.content_length().is_some_and(|len| len > max_size) {
+            return Err(too_large());
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(failed)? {
+            if (data.len() + chunk.len()) as u64 > max_size {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_server::{self, response};
+
+    use super::*;
+
+    fn serve(status: &str, body: &[u8]) -> String {
+        test_server::serve(response(status, "image/png", body), "/photo.png")
+    }
+
+    #[test]
+    fn fetches_with_limit() {
+        assert_eq!(fetch(&serve("200 OK", b"12345"), 10), Ok(b"12345".to_vec()));
+        let error = fetch(&serve("200 OK", b"12345"), 4).unwrap_err();
+        assert!(error.contains("larger than the limit"), "{error}");
+        let error = fetch(&serve("404 Not Found", b""), 10).unwrap_err();
+        assert!(error.contains("404"), "{error}");
+        assert!(fetch("file:///etc/passwd", 10).is_err());
+    }
+
+    #[test]
+    fn parses_sources() {
+        let args: Arguments = serde_json::from_value(serde_json::json!({
+            "source": { "url": "https://example.com/a.png" },
+            "options": { "maxSize": 1000, "supportedTypes": [] }
+        })).unwrap();
+        assert!(matches!(args.source, ImageSource::Url(url) if url == "https://example.com/a.png"));
+        let source: ImageSource = serde_json::from_str(r#"{ "path": "/tmp/a.png" }"#).unwrap();
+        assert!(matches!(source, ImageSource::Path(path) if path == "/tmp/a.png"));
+        assert!(serde_json::from_str::<ImageSource>(r#"{ "bytes": [1, 2] }"#).is_err());
+    }
+}
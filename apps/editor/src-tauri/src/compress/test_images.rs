@@ -0,0 +1,40 @@
+use gif::Repeat;
+use image::codecs::gif::GifEncoder;
+use image::{Delay, Frame, Rgba, RgbaImage};
+use num_traits::ToPrimitive;
+
+/// Pseudo-random numbers, the same on every run.
+pub fn noise() -> impl FnMut() -> u32 {
+    let mut seed = 1u32;
+    move || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        seed
+    }
+}
+
+/// An opaque gradient whose blue changes with `frame`.
+pub fn gradient(width: u32, height: u32, frame: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| Rgba([
+        (x * 255 / width).to_u8().unwrap(),
+        (y * 255 / height).to_u8().unwrap(),
+        (frame * 30 % 256).to_u8().unwrap(),
+        255,
+    ]))
+}
+
+/// Encodes `frames` as a GIF, showing each for 100 ms.
+pub fn animated_gif(frames: impl IntoIterator<Item = RgbaImage>, repeat: Repeat) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut out);
+        encoder.set_repeat(match repeat {
+            Repeat::Infinite => image::codecs::gif::Repeat::Infinite,
+            Repeat::Finite(n) => image::codecs::gif::Repeat::Finite(n),
+        }).unwrap();
+        for img in frames {
+            let delay = Delay::from_numer_denom_ms(100, 1);
+            encoder.encode_frame(Frame::from_parts(img, 0, 0, delay)).unwrap();
+        }
+    }
+    out
+}
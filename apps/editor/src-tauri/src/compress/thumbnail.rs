@@ -0,0 +1,198 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::SystemTime;
+
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use tauri::http::{Response, StatusCode, Uri, header};
+
+use super::{
+    Decoded, ImageCache, OutputFormat, decode, guess_format, keeps_profile, prepare_image,
+    try_compress_size,
+};
+
+/// Total size of the cached thumbnails, in bytes
+pub const THUMBNAIL_CACHE_CAPACITY: u64 = 64 << 20;
+/// Longest side of a thumbnail when the URI gives no `size`
+const DEFAULT_SIZE: u32 = 256;
+/// Larger sizes are clamped to this
+const MAX_SIZE: u32 = 1024;
+const QUALITY: u8 = 75;
+/// Thumbnails made at the same time. Each source is decoded into memory in
+/// full, which takes 60 MB for a 20 MP photo.
+const MAX_WORKERS: usize = 4;
+/// Part of every key; bump it when thumbnails are made differently.
+const THUMBNAIL_VERSION: u32 = 1;
+
+/// Small previews of local images, cached on disk. An entry is keyed on the
+/// modification time and size of its source too, so that a changed source
+/// gets a new thumbnail and the stale one is eventually evicted.
+pub struct Thumbnails {
+    cache: ImageCache,
+    busy: Mutex<usize>,
+    idle: Condvar,
+}
+
+/// A place among the `MAX_WORKERS`, given back when dropped.
+struct Slot<'a>(&'a Thumbnails);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap() -= 1;
+        self.0.idle.notify_one();
+    }
+}
+
+impl Thumbnails {
+    pub fn new(dir: PathBuf, capacity: u64) -> Self {
+        Thumbnails { cache: ImageCache::new(dir, capacity), busy: Mutex::new(0), idle: Condvar::new() }
+    }
+
+    fn key(path: &Path, modified: SystemTime, len: u64, size: u32) -> String {
+        let since = modified.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let mut hasher = Sha256::new();
+        hasher.update(THUMBNAIL_VERSION.to_le_bytes());
+        hasher.update(path.as_os_str().as_encoded_bytes());
+        hasher.update(since.as_nanos().to_le_bytes());
+        hasher.update(len.to_le_bytes());
+        hasher.update(size.to_le_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Waits until fewer than `MAX_WORKERS` thumbnails are being made.
+    fn slot(&self) -> Slot<'_> {
+        let mut busy = self.idle
+            .wait_while(self.busy.lock().unwrap(), |n| *n >= MAX_WORKERS)
+            .unwrap();
+        *busy += 1;
+        Slot(self)
+    }
+
+    /// Returns the thumbnail of the image at `path`, from the cache if the
+    /// image has not changed since it was made.
+    fn get(&self, path: &Path, size: u32) -> io::Result<Result<Vec<u8>, String>> {
+        let metadata = fs::metadata(path)?;
+        let key = Self::key(path, metadata.modified()?, metadata.len(), size);
+        if let Some(data) = self.cache.get(&key) {
+            return Ok(Ok(data));
+        }
+        let _slot = self.slot();
+        let original = fs::read(path)?;
+        let result = thumbnail(&original, size);
+        if let Ok(data) = &result
+            && let Err(e) = self.cache.put(&key, data)
+        {
+            log::warn!("thumbnail: cannot cache {}: {e}", path.display());
+        }
+        Ok(result)
+    }
+}
+
+/// Scales an encoded image down so that its longest side is at most `size`,
+/// as JPEG, or as WebP when it has transparency.
+fn thumbnail(original: &[u8], size: u32) -> Result<Vec<u8>, String> {
+    let Decoded { img, icc, .. } = decode(original, guess_format(original)?)?;
+    let (alpha, img) = prepare_image(&img, None);
+    let format = if alpha { OutputFormat::WebpLossy } else { OutputFormat::Jpeg };
+    let scaling = (f64::from(size) / f64::from(img.width().max(img.height()))).min(1.0);
+    let icc = icc.filter(|_| keeps_profile(format));
+    let (_, data) = try_compress_size(&img, scaling, QUALITY, &[format], icc.as_deref())?;
+    Ok(data)
+}
+
+fn status(code: StatusCode) -> Response<Vec<u8>> {
+    Response::builder().status(code).body(Vec::new()).unwrap()
+}
+
+/// Handles the `thumb` URI scheme. The path is that of the image,
+/// percent-encoded as a whole the way `convertFileSrc` does it, and the
+/// query may give the longest side as `size`.
+#[allow(clippy::missing_panics_doc)]
+pub fn serve_thumbnail(thumbnails: &Thumbnails, uri: &Uri) -> Response<Vec<u8>> {
+    let path = percent_decode_str(uri.path().trim_start_matches('/')).decode_utf8_lossy();
+    let size = uri.query()
+        .and_then(|query| query.split('&').find_map(|x| x.strip_prefix("size=")))
+        .map_or(Ok(DEFAULT_SIZE), str::parse::<u32>);
+    let Ok(size @ 1..) = size else {
+        return status(StatusCode::BAD_REQUEST);
+    };
+    match thumbnails.get(Path::new(path.as_ref()), size.min(MAX_SIZE)) {
+        Ok(Ok(data)) => Response::builder()
+            .header(header::CONTENT_TYPE,
+                guess_format(&data).map_or("application/octet-stream", |f| f.to_mime_type()))
+            // the webview must ask again to see a changed source
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(data)
+            .unwrap(),
+        Ok(Err(e)) => {
+            log::warn!("failed to make thumbnail of {path}: {e}");
+            status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => status(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::warn!("failed to serve thumbnail of {path}: {e}");
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::Duration;
+
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+
+    use super::*;
+
+    fn uri(path: &Path, query: &str) -> Uri {
+        let path = utf8_percent_encode(path.to_str().unwrap(), NON_ALPHANUMERIC);
+        format!("thumb://localhost/{path}{query}").parse().unwrap()
+    }
+
+    fn decoded(response: &Response<Vec<u8>>) -> DynamicImage {
+        image::load_from_memory(response.body()).unwrap()
+    }
+
+    #[test]
+    fn serves_cached_thumbnails() {
+        let dir = tempfile::tempdir().unwrap();
+        let thumbnails = Thumbnails::new(dir.path().join("thumbnails"), 1 << 20);
+        let path = dir.path().join("photo one.png");
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 48, Rgba([255, 0, 0, 255])))
+            .save(&path).unwrap();
+
+        let response = serve_thumbnail(&thumbnails, &uri(&path, "?size=32"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        let img = decoded(&response);
+        assert_eq!((img.width(), img.height()), (32, 24));
+        // never enlarged
+        let img = decoded(&serve_thumbnail(&thumbnails, &uri(&path, "")));
+        assert_eq!((img.width(), img.height()), (64, 48));
+        let entries = || fs::read_dir(dir.path().join("thumbnails")).unwrap().count();
+        serve_thumbnail(&thumbnails, &uri(&path, "?size=32"));
+        assert_eq!(entries(), 2);
+
+        // a changed source is made again
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 48, Rgba([0, 0, 255, 0])))
+            .save(&path).unwrap();
+        File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        let response = serve_thumbnail(&thumbnails, &uri(&path, "?size=32"));
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert!(decoded(&response).color().has_alpha());
+        assert_eq!(entries(), 3);
+
+        let status = |path: &Path, query| serve_thumbnail(&thumbnails, &uri(path, query)).status();
+        assert_eq!(status(&path, "?size=0"), StatusCode::BAD_REQUEST);
+        assert_eq!(status(&path, "?size=big"), StatusCode::BAD_REQUEST);
+        assert_eq!(status(&dir.path().join("missing.png"), ""), StatusCode::NOT_FOUND);
+        let text = dir.path().join("notes.txt");
+        fs::write(&text, b"not an image").unwrap();
+        assert_eq!(status(&text, ""), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
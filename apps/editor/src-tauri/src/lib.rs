@@ -1,14 +1,25 @@
 use std::{panic, sync::{Arc, Mutex}};
 
 use serde::Serialize;
+use tauri::Manager;
 
 mod archive;
 mod compress;
 mod font_registry;
+mod operation;
+#[cfg(test)]
+mod test_server;
 
-use archive::{archive, unarchive};
-use compress::compress_image;
+use archive::{
+    Bundles, archive, close_archive, inspect_assets, open_archive, save_archive, serve_asset,
+    unarchive, verify_archive,
+};
+use compress::{
+    THUMBNAIL_CACHE_CAPACITY, Thumbnails, compress_image, compress_images, crop_image,
+    probe_image, serve_thumbnail,
+};
 use font_registry::{FontRegistry, init_font_registry, pack_fonts};
+use operation::{Operations, cancel_operation};
 
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase", tag = "event", content = "data")]
@@ -86,10 +97,38 @@ pub fn run() {
                 .build(),
         )
         .manage(Arc::new(Mutex::new(Option::<FontRegistry>::None)))
+        .manage(Arc::new(Operations::default()))
+        .manage(Arc::new(Bundles::default()))
+        .setup(|app| {
+            let dir = app.path().app_cache_dir()?.join("thumbnails");
+            app.manage(Arc::new(Thumbnails::new(dir, THUMBNAIL_CACHE_CAPACITY)));
+            Ok(())
+        })
+        .register_asynchronous_uri_scheme_protocol("emmm-asset", |ctx, request, responder| {
+            let bundles = ctx.app_handle().state::<Arc<Bundles>>().inner().clone();
+            tauri::async_runtime::spawn_blocking(move || {
+                responder.respond(serve_asset(&bundles, request.uri()));
+            });
+        })
+        .register_asynchronous_uri_scheme_protocol("thumb", |ctx, request, responder| {
+            let thumbnails = ctx.app_handle().state::<Arc<Thumbnails>>().inner().clone();
+            tauri::async_runtime::spawn_blocking(move || {
+                responder.respond(serve_thumbnail(&thumbnails, request.uri()));
+            });
+        })
         .invoke_handler(tauri::generate_handler![
             compress_image,
+            compress_images,
+            crop_image,
+            probe_image,
             archive,
             unarchive,
+            open_archive,
+            close_archive,
+            save_archive,
+            verify_archive,
+            inspect_assets,
+            cancel_operation,
             init_font_registry,
             pack_fonts,
         ])
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tauri::State;
+
+/// Error with which an operation stops after being cancelled.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Flag polled by a long-running operation to find out whether it should stop.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() { Err(Cancelled) } else { Ok(()) }
+    }
+}
+
+/// Running operations that the frontend can cancel by the id it chose when
+/// starting them.
+#[derive(Default)]
+pub struct Operations {
+    tokens: Mutex<HashMap<String, CancellationToken>>,
+}
+
+impl Operations {
+    /// Registers an operation under `id`. It stays registered until the
+    /// returned handle is dropped.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn register(self: &Arc<Self>, id: String) -> Operation {
+        let token = CancellationToken::default();
+        self.tokens.lock().unwrap().insert(id.clone(), token.clone());
+        Operation { registry: self.clone(), id, token }
+    }
+
+    fn cancel(&self, id: &str) -> bool {
+        match self.tokens.lock().unwrap().get(id) {
+            Some(token) => { token.cancel(); true }
+            None => false,
+        }
+    }
+}
+
+pub struct Operation {
+    registry: Arc<Operations>,
+    id: String,
+    token: CancellationToken,
+}
+
+impl Operation {
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let mut tokens = self.registry.tokens.lock().unwrap();
+        // the id may have been reused by a newer operation in the meantime
+        if tokens.get(&self.id).is_some_and(|t| Arc::ptr_eq(&t.0, &self.token.0)) {
+            tokens.remove(&self.id);
+        }
+    }
+}
+
+/// Returns `false` if no operation with this id is running.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_operation(id: String, operations: State<'_, Arc<Operations>>) -> bool {
+    log::info!("cancelling operation {id}");
+    operations.cancel(&id)
+}
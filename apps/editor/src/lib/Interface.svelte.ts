@@ -5,6 +5,7 @@ import * as Color from "colorjs.io/fn";
 import * as emmm from '@the_dissidents/libemmm';
 import { convertFileSrc } from "@tauri-apps/api/core";
 import { CustomHTMLRenderer } from "./emmm/Custom";
+import { RustAPI } from "./RustAPI";
 import type { EmmmParseData } from "./editor/ParseData";
 import type Editor from "./editor/Editor.svelte";
 import { Memorized } from "./config/Memorized.svelte";
@@ -61,6 +62,9 @@ export const Interface = $state({
     invertedPreview: Memorized.$('invertedPreview', z.boolean(), false),
     syncScrolling: Memorized.$('syncScrolling', z.boolean(), false),
 
+    /** id of the bundle opened in place, which `asset:` references resolve to */
+    bundle: undefined as string | undefined,
+
     activeEditor: undefined as Editor | undefined,
     sourceEditor: undefined as Editor | undefined,
 
@@ -78,6 +82,17 @@ export const Interface = $state({
     onFrameDOMLoaded: new EventHost(),
     onFrameLoaded: new EventHost(),
 
+    /**
+     * Replaces the source, closing the open bundle unless the new source comes
+     * from it.
+     */
+    async replaceSource(source: string, bundle?: string) {
+        if (this.bundle !== undefined && this.bundle !== bundle)
+            await RustAPI.closeArchive(this.bundle);
+        this.bundle = bundle;
+        this.source.set(source);
+    },
+
     requestRender(t = 500) {
         if (!renderTimer)
             renderTimer = setTimeout(() => {
@@ -136,6 +151,8 @@ export const Interface = $state({
         let renderConfig = emmm.RenderConfiguration.from(CustomHTMLRenderer);
         renderConfig.options.transformAsset = (url) => {
             // FIXME: shaky
+            if (url.startsWith('asset:') && this.bundle !== undefined)
+                return RustAPI.assetUrl(this.bundle, url.substring(6));
             if (!url.startsWith('file:')) return undefined;
             return convertFileSrc(url.substring(5));
         };
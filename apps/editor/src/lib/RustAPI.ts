@@ -1,8 +1,5 @@
-import { Channel, invoke } from "@tauri-apps/api/core";
+import { Channel, convertFileSrc, invoke } from "@tauri-apps/api/core";
 import { BinaryReader } from "./details/BinaryReader";
-import { path } from "@tauri-apps/api";
-import * as fs from "@tauri-apps/plugin-fs";
-import mime from 'mime/lite';
 import { readUrl } from "./Util";
 
 type BackendEvent = {
@@ -31,6 +28,71 @@ class BackendError extends Error {
     }
 }
 
+export class CancelledError extends Error {
+    constructor() {
+        super('operation cancelled');
+        this.name = 'CancelledError';
+    }
+}
+
+type OperationError = {
+    kind: 'cancelled'
+} | {
+    kind: 'collision',
+    data: {
+        path: string
+    }
+} | {
+    kind: 'limitExceeded',
+    data: {
+        entry: string,
+        limit: 'totalSize' | 'entrySize' | 'entryCount' | 'compressionRatio',
+        max: number
+    }
+} | {
+    kind: 'failed',
+    data: {
+        msg: string
+    }
+};
+
+function toError(e: unknown) {
+    const error = e as OperationError;
+    switch (error?.kind) {
+    case 'cancelled':
+        return new CancelledError();
+    case 'collision':
+        return new BackendError(`file already exists: ${error.data.path}`);
+    case 'limitExceeded':
+        return new BackendError(
+            `${error.data.entry} exceeds the ${error.data.limit} limit of ${error.data.max}`);
+    case 'failed':
+        return new BackendError(error.data.msg);
+    default:
+        return e;
+    }
+}
+
+/**
+ * Runs a backend operation that can be stopped through `signal`. The operation
+ * rejects with a `CancelledError` after being cancelled.
+ */
+async function cancellable<T>(
+    signal: AbortSignal | undefined,
+    run: (id: string) => Promise<T>
+): Promise<T> {
+    const id = crypto.randomUUID();
+    const onAbort = () => invoke('cancel_operation', { id });
+    signal?.addEventListener('abort', onAbort);
+    try {
+        return await run(id);
+    } catch (e) {
+        throw toError(e);
+    } finally {
+        signal?.removeEventListener('abort', onAbort);
+    }
+}
+
 function createChannel(handler: {[key in BackendEventKey]?: BackendEventHandler<key>}) {
     const channel = new Channel<BackendEvent>;
     channel.onmessage = (msg) => {
@@ -60,6 +122,214 @@ export type PackedFont = {
     data: Uint8ClampedArray<ArrayBuffer>
 };
 
+export type ArchiveProgress = {
+    progress: number,
+    bytesDone: number,
+    bytesTotal: number,
+    /** name of the file being processed */
+    current: string | null
+};
+
+export type ArchiveOptions = {
+    remote?: {
+        /** bundle `http:` and `https:` images too */
+        fetch?: boolean,
+        /** seconds a single download may take */
+        timeout?: number,
+        /** largest image accepted, in bytes */
+        maxSize?: number
+    },
+    /** pass images through `compressImage` before storing them */
+    recompress?: {
+        maxWidth?: number,
+        /** budget for each image, in bytes */
+        maxSize: number,
+        /** keep the artist and copyright notice in the EXIF data */
+        keepCopyright?: boolean
+    }
+};
+
+export type CompressOptions = {
+    /**
+     * formats the result may be in. JPEG and PNG are always allowed; add
+     * `image/webp` or `image/avif` to get smaller images where they can be
+     * displayed
+     */
+    supportedTypes?: string[],
+    /** widest the result may be, in pixels; by default it is not scaled down */
+    maxWidth?: number,
+    /**
+     * keep the artist and copyright notice in the EXIF data; the rest of the
+     * metadata is always removed
+     */
+    keepCopyright?: boolean,
+    /** lowest quality (0-100) to accept before scaling the image down */
+    minQuality?: number,
+    /** number of encodings to try at most */
+    maxIterations?: number,
+    /**
+     * compress only the first frame of animations, which are otherwise kept
+     * as animated GIF or WebP
+     */
+    firstFrame?: boolean,
+    /**
+     * RGB color of the page the image is shown on. Images whose transparency
+     * is simple, such as logos, are flattened onto it so that they can be JPEG
+     */
+    background?: [number, number, number],
+    /**
+     * also try PNG with a palette, which makes screenshots and diagrams much
+     * smaller
+     */
+    palette?: boolean
+};
+
+/** what was done to a compressed or cropped image */
+export type ImageInfo = {
+    width: number,
+    height: number,
+    /** after the EXIF orientation is applied */
+    originalWidth: number,
+    originalHeight: number,
+    /** of the lossy encoding, if it is one and the image was encoded */
+    quality: number | null,
+    /** width of the result over that of the original, or of the cropped part */
+    scale: number,
+    /** the original was kept, with its metadata removed */
+    passthrough: boolean
+};
+
+export type BatchItem = {
+    /** position of the image in the list passed to `compressImages` */
+    index: number,
+    /** images finished so far, including this one */
+    done: number,
+    total: number
+} & ({
+    ok: true,
+    blob: Blob,
+    ext: string,
+    mime: string,
+    info: ImageInfo
+} | {
+    ok: false,
+    error: string
+});
+
+export type BatchReport = {
+    compressed: number,
+    failed: number,
+    cancelled: boolean
+};
+
+type ImageSource = { path: string } | { url: string };
+
+/**
+ * Where the backend can read `url` from by itself: local files, and remote
+ * images, which it downloads.
+ */
+function imageSource(url: URL): ImageSource | undefined {
+    if (url.protocol === 'file:')
+        return { path: decodeURIComponent(url.pathname) };
+    if (url.protocol === 'http:' || url.protocol === 'https:')
+        return { url: url.href };
+    return undefined;
+}
+
+function backendCompressOptions(maxSize: number, options: CompressOptions) {
+    return {
+        ...options, maxSize,
+        supportedTypes: options.supportedTypes ?? ['image/jpeg', 'image/png']
+    };
+}
+
+function readBatchItem(buf: ArrayBuffer): BatchItem {
+    const reader = new BinaryReader(buf);
+    const index = reader.readU32();
+    const done = reader.readU32();
+    const total = reader.readU32();
+    const failed = reader.readU8ClampedArray(1)[0] !== 0;
+    if (failed)
+        return { index, done, total, ok: false, error: reader.readString() };
+    return { index, done, total, ok: true, ...readPackedImage(reader.readToEnd()) };
+}
+
+/** layout of packed images; must match `PACK_VERSION` in the backend */
+const PACK_VERSION = 1;
+
+function readPackedImage(buf: ArrayBuffer) {
+    const reader = new BinaryReader(buf);
+    const version = reader.readU32();
+    if (version !== PACK_VERSION)
+        throw new BackendError(`unknown image layout version ${version}`);
+    const { mime, ext, ...info }: { mime: string, ext: string } & ImageInfo =
+        JSON.parse(reader.readString());
+    const data = reader.readToEnd();
+    return {
+        blob: new Blob([data], { type: mime }),
+        ext, mime, info
+    };
+}
+
+export type ArchiveReport = {
+    /** remote images that were left as references */
+    failedDownloads: { url: string, reason: string }[],
+    /** images replaced by a smaller recompressed version */
+    recompressed: number,
+    bytesSaved: number
+};
+
+export type VerifyReport = {
+    /** legacy bundles can only be checked against their CRCs */
+    hasManifest: boolean,
+    /** entries the bundle should have but lacks */
+    missing: string[],
+    /** entries the manifest does not account for */
+    extra: string[],
+    corrupted: {
+        entry: string,
+        corruption:
+            | { kind: 'unreadable', data: { msg: string } }
+            | { kind: 'sizeMismatch', data: { expected: number, actual: number } }
+            | { kind: 'hashMismatch' }
+    }[],
+    /** assets referred to by the source that the bundle lacks */
+    unresolved: string[]
+};
+
+export type AssetInspection = {
+    /** in order of first reference */
+    files: {
+        /** path as written in the source, without `file:` */
+        path: string,
+        count: number,
+        /** null if there is no such file */
+        size: number | null,
+        /** another referenced file has the same name */
+        duplicateName: boolean
+    }[],
+    missing: string[],
+    /** only bundled when remote images are fetched */
+    remote: string[],
+    /** uncompressed, not counting remote images */
+    estimatedSize: number
+};
+
+export type UnarchiveOptions = {
+    /** what to do with files already present in the output directory */
+    onCollision?: 'fail' | 'rename' | 'overwrite',
+    /** extract into a new subdirectory named after the bundle (default) */
+    subdirectory?: boolean,
+    limits?: {
+        /** total uncompressed size in bytes */
+        totalSize?: number,
+        /** uncompressed size of a single entry in bytes */
+        entrySize?: number,
+        entries?: number,
+        compressionRatio?: number
+    }
+};
+
 export const RustAPI = {
     async initFonts() {
         await invoke('init_font_registry');
@@ -81,43 +351,124 @@ export const RustAPI = {
         return fonts;
     },
 
-    async archive(source: string, path: string, onProgress?: (x: number) => void) {
-        const channel = new Channel<{ progress: number }>();
-        channel.onmessage = ({ progress }) => onProgress?.(progress);
-        await invoke('archive', { channel, source, path });
+    async archive(
+        source: string, path: string, options: ArchiveOptions = {},
+        onProgress?: (x: ArchiveProgress) => void, signal?: AbortSignal
+    ) {
+        const channel = new Channel<ArchiveProgress>();
+        channel.onmessage = (x) => onProgress?.(x);
+        return await cancellable(signal, (id) =>
+            invoke<ArchiveReport>('archive', { channel, source, path, options, id }));
+    },
+
+    async unarchive(
+        path: string, output: string, options: UnarchiveOptions = {},
+        onProgress?: (x: ArchiveProgress) => void, signal?: AbortSignal
+    ) {
+        const channel = new Channel<ArchiveProgress>();
+        channel.onmessage = (x) => onProgress?.(x);
+        return await cancellable(signal, (id) =>
+            invoke<{ source: string, directory: string }>('unarchive',
+                { channel, path, output, options, id }));
+    },
+
+    /** Lists what `archive` would put into a bundle for `source`. */
+    async inspectAssets(source: string) {
+        return await invoke<AssetInspection>('inspect_assets', { source });
     },
 
-    async unarchive(path: string, output: string, onProgress?: (x: number) => void) {
-        const channel = new Channel<{ progress: number }>();
-        channel.onmessage = ({ progress }) => onProgress?.(progress);
-        return await invoke('unarchive', { channel, path, output }) as string;
+    /** Checks a bundle without extracting anything. */
+    async verifyArchive(
+        path: string,
+        onProgress?: (x: ArchiveProgress) => void, signal?: AbortSignal
+    ) {
+        const channel = new Channel<ArchiveProgress>();
+        channel.onmessage = (x) => onProgress?.(x);
+        return await cancellable(signal, (id) =>
+            invoke<VerifyReport>('verify_archive', { channel, path, id }));
     },
 
-    async compressImage(url: URL, maxSize: number) {
-        let filepath = decodeURIComponent(url.pathname);
-        if (url.protocol !== 'file:') {
-            let file = new File([await readUrl(url)], url.href,
-                { type: mime.getType(url.href) ?? undefined });
-            // save to local
-            filepath = await path.join(
-                await path.tempDir(),
-                crypto.randomUUID() + await path.extname(filepath));
-            await fs.writeFile(filepath, file.stream());
+    /**
+     * Opens a bundle without extracting it. The source keeps its `asset:`
+     * references; use `assetUrl` to load them.
+     */
+    async openArchive(path: string) {
+        try {
+            return await invoke<{ id: string, source: string }>('open_archive', { path });
+        } catch (e) {
+            throw toError(e);
         }
+    },
 
-        const buf = await invoke<ArrayBuffer>('compress_image', {
-            path: filepath, maxSize,
-            supportedTypes: ['image/jpeg', 'image/png'],
-            max_width: 1920
-        });
+    /**
+     * Saves `source` into a bundle opened with `openArchive`, adding the files
+     * it references and dropping unreferenced assets. Returns the source as
+     * stored, with `file:` references turned into `asset:` ones.
+     */
+    async saveArchive(
+        bundle: string, source: string,
+        onProgress?: (x: ArchiveProgress) => void, signal?: AbortSignal
+    ) {
+        const channel = new Channel<ArchiveProgress>();
+        channel.onmessage = (x) => onProgress?.(x);
+        return await cancellable(signal, (id) =>
+            invoke<string>('save_archive', { channel, bundle, source, id }));
+    },
 
-        const reader = new BinaryReader(buf);
-        const type = reader.readString();
-        const ext = reader.readString();
-        const data = reader.readToEnd();
-        return {
-            blob: new Blob([data], { type }),
-            ext, mime: type
-        };
+    async closeArchive(id: string) {
+        return await invoke<boolean>('close_archive', { id });
+    },
+
+    assetUrl(bundle: string, name: string) {
+        return convertFileSrc(`${bundle}/${name}`, 'emmm-asset');
+    },
+
+    /**
+     * Compresses an image given by URL or as bytes. Images the backend cannot
+     * read by itself, such as `blob:` URLs, are read here and sent as bytes.
+     */
+    async compressImage(
+        image: URL | Uint8Array, maxSize: number, options: CompressOptions = {}
+    ) {
+        const backendOptions = backendCompressOptions(maxSize, options);
+        const source = image instanceof URL ? imageSource(image) : undefined;
+        let buf: ArrayBuffer;
+        if (source) {
+            buf = await invoke<ArrayBuffer>('compress_image',
+                { source, options: backendOptions });
+        } else {
+            const bytes = image instanceof URL
+                ? new Uint8Array(await (await readUrl(image)).arrayBuffer())
+                : image;
+            buf = await invoke<ArrayBuffer>('compress_image', bytes,
+                { headers: { 'compress-options': JSON.stringify(backendOptions) } });
+        }
+        return readPackedImage(buf);
+    },
+
+    /**
+     * Compresses several images in parallel with the same limits. Each result
+     * is passed to `onItem` as soon as it is ready, in no particular order; an
+     * image that fails does not stop the others. Only `file:`, `http:` and
+     * `https:` URLs are accepted.
+     */
+    async compressImages(
+        urls: URL[], maxSize: number, options: CompressOptions = {},
+        onItem?: (x: BatchItem) => void, signal?: AbortSignal
+    ) {
+        const sources = urls.map((url) => {
+            const source = imageSource(url);
+            if (!source) throw new BackendError(`cannot compress ${url.href} in a batch`);
+            return source;
+        });
+        const channel = new Channel<ArrayBuffer>();
+        channel.onmessage = (buf) => onItem?.(readBatchItem(buf));
+        const report = await cancellable(signal, (id) =>
+            invoke<BatchReport>('compress_images', {
+                channel, sources, options: backendCompressOptions(maxSize, options), id
+            }));
+        if (report.cancelled)
+            throw new CancelledError();
+        return report;
     }
 }
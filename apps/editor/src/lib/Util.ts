@@ -99,6 +99,16 @@ export const DOMUtil = {
     }
 }
 
+export function formatBytes(n: number) {
+    const units = ['B', 'KB', 'MB', 'GB'];
+    let i = 0;
+    while (n >= 1024 && i < units.length - 1) {
+        n /= 1024;
+        i++;
+    }
+    return `${i == 0 ? n : n.toFixed(1)} ${units[i]}`;
+}
+
 export async function readUrl(url: URL) {
     console.log(url);
     if (url.protocol == 'file:') {
@@ -2,6 +2,12 @@ import { EditorView } from "@codemirror/view";
 import { getCurrentWebviewWindow } from "@tauri-apps/api/webviewWindow";
 
 const window = getCurrentWebviewWindow();
+
+// so that paths containing separators still read back as a single argument
+function escapeArgument(str: string) {
+    return str.replace(/[\\|;\]$]/g, '\\$&');
+}
+
 window.onDragDropEvent(({payload: ev}) => {
     if (ev.type == 'over') {
         const {x, y} = ev.position; //ev.position.toLogical(factor);
@@ -35,7 +41,7 @@ export const emmmDropImage = EditorView.domEventObservers({
         const pos = view.posAtCoords({x: event.clientX, y: event.clientY});
         if (!pos) return;
 
-        const result = files.map((x) => `[.image file:${x};]`).join('');
+        const result = files.map((x) => `[.image file:${escapeArgument(x)};]`).join('');
         view.dispatch({
             changes: [{
                 from: pos, to: pos,